@@ -0,0 +1,483 @@
+// Deep exrs are not supported by the exr crate yet, so the chunks are read and decompressed here
+// and every pixel is flattened by compositing its samples front to back.
+
+use exr::block::chunk::TileCoordinates;
+use exr::meta::attribute::SampleType;
+use exr::meta::header::Header;
+use exr::meta::{BlockDescription, MetaData};
+use exr::prelude::*;
+
+pub fn is_deep(path: &str) -> bool {
+    MetaData::read_from_file(path, false)
+        .is_ok_and(|meta| meta.headers.iter().any(|header| header.deep))
+}
+
+// read a deep exr and flatten every part into regular channels, keeping the names, attributes and encoding
+pub fn read_flattened(path: &str) -> (ImageAttributes, Layers<AnyChannels<Levels<FlatSamples>>>) {
+    let bytes = std::fs::read(path).expect("couldn't read the deep exr");
+    let mut cursor = std::io::Cursor::new(&bytes[..]);
+    let meta = MetaData::read_from_buffered(&mut cursor, false).expect("couldn't read the deep exr header");
+
+    // the offset tables follow the headers, one table per part
+    let mut position = cursor.position() as usize;
+    let mut offset_tables: Vec<Vec<usize>> = Vec::new();
+    for header in &meta.headers {
+        let table = (0..header.chunk_count)
+            .map(|i| read_u64(&bytes, position + i * 8) as usize)
+            .collect();
+        position += header.chunk_count * 8;
+        offset_tables.push(table);
+    }
+
+    let mut layers: Layers<AnyChannels<Levels<FlatSamples>>> = Layers::new();
+    for (part_index, header) in meta.headers.iter().enumerate() {
+        if !header.deep {
+            panic!("exrs mixing deep and flat parts are not supported: {}", path);
+        }
+
+        let n_pixels = header.layer_size.area();
+        let mut flat: Vec<Values> = header
+            .channels
+            .list
+            .iter()
+            .map(|channel| Values::new(channel.sample_type, n_pixels))
+            .collect();
+
+        for &offset in &offset_tables[part_index] {
+            let mut at = offset;
+            if meta.requirements.is_multilayer() {
+                at += 4; // part number
+            }
+
+            // the block in pixel coordinates of the data window
+            let block = match header.blocks {
+                BlockDescription::ScanLines => {
+                    let y = read_i32(&bytes, at) - header.own_attributes.layer_position.y();
+                    at += 4;
+                    let lines = header
+                        .compression
+                        .scan_lines_per_block()
+                        .min(header.layer_size.y() - y as usize);
+                    IntegerBounds::new((0, y), (header.layer_size.x(), lines))
+                }
+                BlockDescription::Tiles(_) => {
+                    let coordinates = TileCoordinates {
+                        tile_index: Vec2(read_i32(&bytes, at) as usize, read_i32(&bytes, at + 4) as usize),
+                        level_index: Vec2(read_i32(&bytes, at + 8) as usize, read_i32(&bytes, at + 12) as usize),
+                    };
+                    at += 16;
+                    if coordinates.level_index != Vec2(0, 0) {
+                        continue; // only the full resolution level is flattened
+                    }
+                    header
+                        .get_absolute_block_pixel_coordinates(coordinates)
+                        .expect("invalid deep tile")
+                }
+            };
+
+            let packed_table_size = read_u64(&bytes, at) as usize;
+            let packed_samples_size = read_u64(&bytes, at + 8) as usize;
+            let samples_size = read_u64(&bytes, at + 16) as usize;
+            at += 24;
+
+            let table = decompress(
+                header.compression,
+                &bytes[at..at + packed_table_size],
+                block.size.area() * 4,
+            );
+            at += packed_table_size;
+            let samples = decompress(
+                header.compression,
+                &bytes[at..at + packed_samples_size],
+                samples_size,
+            );
+
+            let tiled = matches!(header.blocks, BlockDescription::Tiles(_));
+            flatten_block(header, block, &table, &samples, tiled, &mut flat);
+        }
+
+        let channels = header
+            .channels
+            .list
+            .iter()
+            .zip(flat)
+            .map(|(description, values)| {
+                let samples = match (description.sample_type, values) {
+                    (SampleType::F16, Values::Float(values)) => {
+                        FlatSamples::F16(values.into_iter().map(f16::from_f32).collect())
+                    }
+                    (_, Values::Float(values)) => FlatSamples::F32(values),
+                    (_, Values::Uint(values)) => FlatSamples::U32(values),
+                };
+                AnyChannel {
+                    name: description.name.clone(),
+                    sample_data: Levels::Singular(samples),
+                    quantize_linearly: description.quantize_linearly,
+                    sampling: Vec2(1, 1),
+                }
+            })
+            .collect();
+
+        let encoding = Encoding {
+            compression: header.compression,
+            blocks: match header.blocks {
+                BlockDescription::ScanLines => Blocks::ScanLines,
+                BlockDescription::Tiles(tiles) => Blocks::Tiles(tiles.tile_size),
+            },
+            // scan lines can't be written with an unspecified order
+            line_order: match (header.line_order, header.blocks) {
+                (LineOrder::Unspecified, BlockDescription::ScanLines) => LineOrder::Increasing,
+                (line_order, _) => line_order,
+            },
+        };
+
+        layers.push(Layer::new(
+            header.layer_size,
+            header.own_attributes.clone(),
+            encoding,
+            AnyChannels::sort(channels),
+        ));
+    }
+
+    (meta.headers[0].shared_attributes.clone(), layers)
+}
+
+// the samples of one channel, uint channels (ids) stay integers so ids above 2^24 survive
+enum Values {
+    Float(Vec<f32>),
+    Uint(Vec<u32>),
+}
+
+impl Values {
+    fn new(sample_type: SampleType, len: usize) -> Values {
+        match sample_type {
+            SampleType::U32 => Values::Uint(vec![0; len]),
+            _ => Values::Float(vec![0.0; len]),
+        }
+    }
+
+    fn float(&self, i: usize) -> f32 {
+        match self {
+            Values::Float(values) => values[i],
+            Values::Uint(values) => values[i] as f32,
+        }
+    }
+}
+
+// composite the samples of every pixel in the block front to back into `flat`
+fn flatten_block(
+    header: &Header,
+    block: IntegerBounds,
+    table: &[u8],
+    samples: &[u8],
+    tiled: bool,
+    flat: &mut [Values],
+) {
+    let channels = &header.channels.list;
+    let width = block.size.x();
+    let n_block_pixels = block.size.area();
+    let bytes_per_sample: usize = channels.iter().map(|c| c.sample_type.bytes_per_sample()).sum();
+
+    // the table holds the accumulated sample count, restarting on every line or running through the whole block
+    let accumulated: Vec<usize> = (0..n_block_pixels).map(|i| read_i32(table, i * 4) as usize).collect();
+    let per_line_total: usize = (0..block.size.y()).map(|y| accumulated[y * width + width - 1]).sum();
+    let restarts_per_line = per_line_total * bytes_per_sample == samples.len();
+
+    let mut counts = vec![0; n_block_pixels];
+    for i in 0..n_block_pixels {
+        let previous = match i {
+            0 => 0,
+            i if restarts_per_line && i % width == 0 => 0,
+            i => accumulated[i - 1],
+        };
+        counts[i] = accumulated[i] - previous;
+    }
+    let mut first_sample = vec![0; n_block_pixels + 1];
+    for i in 0..n_block_pixels {
+        first_sample[i + 1] = first_sample[i] + counts[i];
+    }
+
+    // scan line blocks store every line channel by channel, tiles store the whole tile channel by channel
+    let rows: Vec<std::ops::Range<usize>> = if tiled {
+        std::iter::once(0..n_block_pixels).collect()
+    } else {
+        (0..block.size.y()).map(|y| y * width..(y + 1) * width).collect()
+    };
+
+    let n_samples = first_sample[n_block_pixels];
+    let mut values: Vec<Values> = channels
+        .iter()
+        .map(|channel| Values::new(channel.sample_type, n_samples))
+        .collect();
+    let mut at = 0;
+    for row in rows {
+        let row_samples = first_sample[row.start]..first_sample[row.end];
+        for (channel_index, channel) in channels.iter().enumerate() {
+            for sample in row_samples.clone() {
+                match &mut values[channel_index] {
+                    Values::Float(values) => values[sample] = read_sample(samples, at, channel.sample_type),
+                    Values::Uint(values) => values[sample] = read_u32(samples, at),
+                }
+                at += channel.sample_type.bytes_per_sample();
+            }
+        }
+    }
+
+    let names: Vec<String> = channels.iter().map(|c| c.name.to_string()).collect();
+    let depth = names.iter().position(|name| name == "Z");
+    let alphas: Vec<Option<usize>> = names.iter().map(|name| alpha_for(name, &names)).collect();
+
+    for i in 0..n_block_pixels {
+        let mut order: Vec<usize> = (first_sample[i]..first_sample[i + 1]).collect();
+        if let Some(depth) = depth {
+            order.sort_by(|a, b| values[depth].float(*a).total_cmp(&values[depth].float(*b)));
+        }
+        let Some(&front) = order.first() else { continue };
+
+        let x = i % width + block.position.x() as usize;
+        let y = i / width + block.position.y() as usize;
+        let pixel = x + y * header.layer_size.x();
+
+        for (channel_index, name) in names.iter().enumerate() {
+            match (&mut flat[channel_index], &values[channel_index]) {
+                // ids can't be blended, take the front most sample
+                (Values::Uint(flat), Values::Uint(values)) => flat[pixel] = values[front],
+                // neither can depth
+                (Values::Float(flat), Values::Float(values)) if name == "Z" || name == "ZBack" => {
+                    flat[pixel] = values[front]
+                }
+                (Values::Float(flat), Values::Float(channel_values)) => {
+                    let mut value = 0.0;
+                    let mut transparency = 1.0;
+                    for &sample in &order {
+                        value += transparency * channel_values[sample];
+                        if let Some(alpha) = alphas[channel_index] {
+                            transparency *= 1.0 - values[alpha].float(sample);
+                        }
+                    }
+                    flat[pixel] = value;
+                }
+                _ => unreachable!(),
+            }
+        }
+    }
+}
+
+// the alpha of the channel group, e.g. diffuse.A for diffuse.R, falling back to A
+fn alpha_for(name: &str, names: &[String]) -> Option<usize> {
+    let group_alpha = match name.rsplit_once('.') {
+        Some((prefix, _)) => format!("{}.A", prefix),
+        None => String::from("A"),
+    };
+    names
+        .iter()
+        .position(|n| *n == group_alpha)
+        .or_else(|| names.iter().position(|n| n == "A"))
+}
+
+fn decompress(compression: Compression, packed: &[u8], size: usize) -> Vec<u8> {
+    // blocks that didn't get smaller are stored uncompressed
+    if packed.len() == size {
+        return packed.to_vec();
+    }
+
+    let mut bytes = match compression {
+        Compression::Uncompressed => return packed.to_vec(),
+        Compression::RLE => decompress_rle(packed),
+        Compression::ZIP1 | Compression::ZIP16 => {
+            miniz_oxide::inflate::decompress_to_vec_zlib(packed).expect("invalid zip data in the deep exr")
+        }
+        _ => panic!("{} is not allowed for deep data", compression),
+    };
+    if bytes.len() != size {
+        panic!("invalid {} data in the deep exr", compression);
+    }
+
+    // undo the predictor and the split into even and odd bytes, both rle and zip use them
+    for i in 1..bytes.len() {
+        bytes[i] = bytes[i - 1].wrapping_add(bytes[i]).wrapping_sub(128);
+    }
+    let (first, second) = bytes.split_at(bytes.len().div_ceil(2));
+    let mut out = Vec::with_capacity(bytes.len());
+    for i in 0..first.len() {
+        out.push(first[i]);
+        if i < second.len() {
+            out.push(second[i]);
+        }
+    }
+    out
+}
+
+fn decompress_rle(packed: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < packed.len() {
+        let count = packed[i] as i8;
+        i += 1;
+        if count < 0 {
+            let n = (-(count as i32)) as usize;
+            out.extend_from_slice(&packed[i..i + n]);
+            i += n;
+        } else {
+            out.extend(std::iter::repeat_n(packed[i], count as usize + 1));
+            i += 1;
+        }
+    }
+    out
+}
+
+fn read_sample(bytes: &[u8], at: usize, sample_type: SampleType) -> f32 {
+    match sample_type {
+        SampleType::F16 => f16::from_le_bytes([bytes[at], bytes[at + 1]]).to_f32(),
+        SampleType::F32 => f32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()),
+        SampleType::U32 => read_u32(bytes, at) as f32,
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_i32(bytes: &[u8], at: usize) -> i32 {
+    i32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exr::meta::attribute::ChannelDescription;
+
+    // the reverse of what decompress undoes: split into even and odd bytes, then the predictor
+    fn predict(bytes: &[u8]) -> Vec<u8> {
+        let half = bytes.len().div_ceil(2);
+        let mut split = vec![0; bytes.len()];
+        for (i, byte) in bytes.iter().enumerate() {
+            match i % 2 {
+                0 => split[i / 2] = *byte,
+                _ => split[half + i / 2] = *byte,
+            }
+        }
+        let mut out = split.clone();
+        for i in 1..split.len() {
+            out[i] = split[i].wrapping_sub(split[i - 1]).wrapping_add(128);
+        }
+        out
+    }
+
+    // 2x2 pixels with 2, 1, 0 and 1 samples as (A, R, Z, id), the first pixel has its samples back to front
+    fn pixel_samples() -> Vec<Vec<(f32, f32, f32, u32)>> {
+        vec![
+            vec![(0.5, 0.5, 5.0, 1), (0.5, 0.25, 1.0, (1 << 24) + 1)],
+            vec![(1.0, 3.0, 2.0, 7)],
+            vec![],
+            vec![(0.25, 0.1, 3.0, 9)],
+        ]
+    }
+
+    fn header() -> Header {
+        let channels = exr::prelude::SmallVec::from_vec(vec![
+            ChannelDescription::named("A", SampleType::F32),
+            ChannelDescription::named("R", SampleType::F32),
+            ChannelDescription::named("Z", SampleType::F32),
+            ChannelDescription::named("id", SampleType::U32),
+        ]);
+        Header::new(Text::from("deep"), (2, 2), channels)
+    }
+
+    // the samples channel by channel for every `chunk` pixels, a line for scan lines and the whole block for tiles
+    fn sample_bytes(chunk: usize) -> Vec<u8> {
+        let pixels = pixel_samples();
+        let mut out = Vec::new();
+        for row in pixels.chunks(chunk) {
+            let samples: Vec<_> = row.iter().flatten().collect();
+            for channel in 0..4 {
+                for (a, r, z, id) in &samples {
+                    let bytes = match channel {
+                        0 => a.to_le_bytes(),
+                        1 => r.to_le_bytes(),
+                        2 => z.to_le_bytes(),
+                        _ => id.to_le_bytes(),
+                    };
+                    out.extend_from_slice(&bytes);
+                }
+            }
+        }
+        out
+    }
+
+    fn table_bytes(accumulated: &[i32]) -> Vec<u8> {
+        accumulated.iter().flat_map(|count| count.to_le_bytes()).collect()
+    }
+
+    fn flatten(table: &[i32], samples: &[u8], tiled: bool) -> Vec<Values> {
+        let header = header();
+        let mut flat: Vec<Values> = header
+            .channels
+            .list
+            .iter()
+            .map(|channel| Values::new(channel.sample_type, 4))
+            .collect();
+        let block = IntegerBounds::new((0, 0), (2, 2));
+        flatten_block(&header, block, &table_bytes(table), samples, tiled, &mut flat);
+        flat
+    }
+
+    fn assert_flattened(flat: &[Values]) {
+        let floats = |channel: usize| match &flat[channel] {
+            Values::Float(values) => values.clone(),
+            Values::Uint(_) => panic!("channel {} should be float", channel),
+        };
+        // the Z = 1 sample is in front: 0.25 + 0.5 * 0.5 and 0.5 + 0.5 * 0.5
+        assert_eq!(floats(0), vec![0.75, 1.0, 0.0, 0.25]);
+        assert_eq!(floats(1), vec![0.5, 3.0, 0.0, 0.1]);
+        assert_eq!(floats(2), vec![1.0, 2.0, 0.0, 3.0]);
+        match &flat[3] {
+            Values::Uint(ids) => assert_eq!(ids, &vec![(1 << 24) + 1, 7, 0, 9]),
+            Values::Float(_) => panic!("ids should stay integers"),
+        }
+    }
+
+    #[test]
+    fn rle() {
+        let packed = [2, 7, (-3i8) as u8, 1, 2, 3, 0, 4];
+        assert_eq!(decompress_rle(&packed), vec![7, 7, 7, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn zip_predictor_and_interleave() {
+        let bytes: Vec<u8> = (0..101).map(|i| (i * 7 % 13) as u8).collect();
+        let packed = miniz_oxide::deflate::compress_to_vec_zlib(&predict(&bytes), 6);
+        assert_eq!(decompress(Compression::ZIP1, &packed, bytes.len()), bytes);
+    }
+
+    #[test]
+    fn rle_predictor_and_interleave() {
+        let bytes: Vec<u8> = (0..20).map(|i| (i % 3) as u8).collect();
+        // a single literal run
+        let mut packed = vec![(-(bytes.len() as i8)) as u8];
+        packed.extend(predict(&bytes));
+        assert_eq!(decompress(Compression::RLE, &packed, bytes.len()), bytes);
+    }
+
+    #[test]
+    fn table_restarting_every_line() {
+        let samples = sample_bytes(2);
+        assert_flattened(&flatten(&[2, 3, 0, 1], &samples, false));
+    }
+
+    #[test]
+    fn table_running_through_the_block() {
+        let samples = sample_bytes(2);
+        assert_flattened(&flatten(&[2, 3, 3, 4], &samples, false));
+    }
+
+    #[test]
+    fn tile_channel_by_channel() {
+        let samples = sample_bytes(4);
+        assert_flattened(&flatten(&[2, 3, 3, 4], &samples, true));
+    }
+}
@@ -0,0 +1,423 @@
+// A thin wrapper around the oidn c api. The oidn crate's RayTracing filter can only bind the color image and
+// sets cleanAux under the wrong name, prefiltering the albedo and normal needs filters bound to those images.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+
+use oidn::sys::*;
+
+// OIDN_QUALITY_FAST, added in oidn 2.2 and missing from the bindings
+const QUALITY_FAST: i32 = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Quality {
+    Default, // high for oidn 2.x
+    Fast,
+    Balanced,
+    High,
+}
+
+impl Quality {
+    pub fn from_name(name: &str) -> Quality {
+        match name {
+            "default" => Quality::Default,
+            "fast" => Quality::Fast,
+            "balanced" => Quality::Balanced,
+            "high" => Quality::High,
+            _ => panic!("unknown quality: {}", name),
+        }
+    }
+
+    fn as_raw(&self) -> i32 {
+        match self {
+            Quality::Default => OIDNQuality_OIDN_QUALITY_DEFAULT as i32,
+            Quality::Fast => QUALITY_FAST,
+            Quality::Balanced => OIDNQuality_OIDN_QUALITY_BALANCED as i32,
+            Quality::High => OIDNQuality_OIDN_QUALITY_HIGH as i32,
+        }
+    }
+}
+
+// what the color values of the beauty are
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Range {
+    Hdr,  // linear, any value (renders)
+    Ldr,  // linear, 0 to 1 (tone mapped stills, bakes)
+    Srgb, // sRGB encoded, 0 to 1 (textures, display referred images)
+}
+
+impl Range {
+    pub fn from_name(name: &str) -> Range {
+        match name {
+            "hdr" => Range::Hdr,
+            "ldr" => Range::Ldr,
+            "srgb" => Range::Srgb,
+            _ => panic!("unknown range: {}", name),
+        }
+    }
+}
+
+// the value the colors are multiplied with before denoising (and divided by afterwards)
+#[derive(Clone, Copy, Debug)]
+pub enum InputScale {
+    Default, // oidn estimates it for hdr and uses 1 for ldr
+    Auto,    // estimated here for every image and printed
+    Fixed(f32),
+}
+
+impl InputScale {
+    pub fn from_name(name: &str) -> InputScale {
+        match name {
+            "default" => InputScale::Default,
+            "auto" => InputScale::Auto,
+            value => InputScale::Fixed(value.parse().expect("--input-scale has to be a number, auto or default")),
+        }
+    }
+}
+
+// the exposure estimation of oidn: the log average luminance of 16x16 pixel blocks mapped to middle grey
+pub fn auto_exposure(rgb_data: &[f32], width: usize, height: usize) -> f32 {
+    const KEY: f32 = 0.18;
+    const EPS: f32 = 1e-8;
+    const BLOCK_SIZE: usize = 16;
+
+    let mut log_sum = 0.0;
+    let mut count = 0;
+    for block_y in (0..height).step_by(BLOCK_SIZE) {
+        for block_x in (0..width).step_by(BLOCK_SIZE) {
+            let mut luminance = 0.0;
+            let mut n_pixels = 0;
+            for y in block_y..(block_y + BLOCK_SIZE).min(height) {
+                for x in block_x..(block_x + BLOCK_SIZE).min(width) {
+                    let i = (x + y * width) * 3;
+                    let (r, g, b) = (rgb_data[i], rgb_data[i + 1], rgb_data[i + 2]);
+                    luminance += 0.212671 * r.max(0.0) + 0.715160 * g.max(0.0) + 0.072169 * b.max(0.0);
+                    n_pixels += 1;
+                }
+            }
+            luminance /= n_pixels as f32;
+            if luminance > EPS {
+                log_sum += luminance.log2();
+                count += 1;
+            }
+        }
+    }
+
+    if count > 0 {
+        KEY / (log_sum / count as f32).exp2()
+    } else {
+        1.0
+    }
+}
+
+// the first two bytes of every .tza weights file
+const TZA_MAGIC: u16 = 0x41D7;
+
+// read a .tza weights file, `name` is a path or the name of a file in $OIDN_CMD_WEIGHTS or ~/.config/oidn-cmd/weights
+pub fn load_weights(name: &str) -> (String, Vec<u8>) {
+    let mut candidates = vec![std::path::PathBuf::from(name)];
+    let file_name = match name.ends_with(".tza") {
+        true => name.to_string(),
+        false => format!("{}.tza", name),
+    };
+    if let Ok(dir) = std::env::var("OIDN_CMD_WEIGHTS") {
+        candidates.push(std::path::Path::new(&dir).join(&file_name));
+    }
+    if let Ok(home) = std::env::var("HOME") {
+        candidates.push(std::path::Path::new(&home).join(".config/oidn-cmd/weights").join(&file_name));
+    }
+
+    let path = candidates
+        .into_iter()
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| panic!("weights not found: {} (a .tza file or a name in $OIDN_CMD_WEIGHTS or ~/.config/oidn-cmd/weights)", name));
+    let bytes = std::fs::read(&path).expect("couldn't read the weights");
+    if bytes.len() < 2 || u16::from_le_bytes([bytes[0], bytes[1]]) != TZA_MAGIC {
+        panic!("{} is not a .tza weights file", path.display());
+    }
+    (path.display().to_string(), bytes)
+}
+
+// whether the device oidn picks by default (the first physical device) is the cpu
+pub fn default_device_is_cpu() -> bool {
+    let name = CString::new("type").unwrap();
+    unsafe {
+        oidnGetNumPhysicalDevices() > 0
+            && oidnGetPhysicalDeviceInt(0, name.as_ptr()) as OIDNDeviceType == OIDNDeviceType_OIDN_DEVICE_TYPE_CPU
+    }
+}
+
+pub struct Device(OIDNDevice);
+
+// cpu settings, None keeps the oidn default (all cores, affinity picked by oidn)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeviceOptions {
+    pub threads: Option<i32>,
+    pub affinity: Option<bool>,
+}
+
+impl Device {
+    // the fastest device available, or the cpu when thread settings are given as they only exist there
+    pub fn new(options: &DeviceOptions) -> Device {
+        let device_type = match options.threads.is_some() || options.affinity.is_some() {
+            true => OIDNDeviceType_OIDN_DEVICE_TYPE_CPU,
+            false => OIDNDeviceType_OIDN_DEVICE_TYPE_DEFAULT,
+        };
+        let handle = unsafe { oidnNewDevice(device_type) };
+        if handle.is_null() {
+            panic!("couldn't create an oidn device");
+        }
+        let device = Device(handle);
+        if let Some(threads) = options.threads {
+            device.set_int("numThreads", threads);
+        }
+        if let Some(affinity) = options.affinity {
+            device.set_bool("setAffinity", affinity);
+        }
+        unsafe { oidnCommitDevice(handle) };
+        if let Err(e) = device.get_error() {
+            panic!("couldn't create an oidn device: {}", e);
+        }
+        device
+    }
+
+    // the device type and the effective cpu settings, e.g. "cpu, 8 threads, affinity on"
+    pub fn describe(&self) -> String {
+        let device_type = self.get_int("type") as OIDNDeviceType;
+        let name = [
+            (OIDNDeviceType_OIDN_DEVICE_TYPE_CPU, "cpu"),
+            (OIDNDeviceType_OIDN_DEVICE_TYPE_SYCL, "sycl"),
+            (OIDNDeviceType_OIDN_DEVICE_TYPE_CUDA, "cuda"),
+            (OIDNDeviceType_OIDN_DEVICE_TYPE_HIP, "hip"),
+            (OIDNDeviceType_OIDN_DEVICE_TYPE_METAL, "metal"),
+        ]
+        .iter()
+        .find(|(known_type, _)| *known_type == device_type)
+        .map_or("unknown", |(_, name)| *name);
+        if device_type != OIDNDeviceType_OIDN_DEVICE_TYPE_CPU {
+            return name.to_string();
+        }
+        let threads = match self.get_int("numThreads") {
+            0 => String::from("all"),
+            threads => threads.to_string(),
+        };
+        let affinity = match self.get_bool("setAffinity") {
+            true => "on",
+            false => "off",
+        };
+        format!("{}, {} threads, affinity {}", name, threads, affinity)
+    }
+
+    fn set_int(&self, name: &str, value: i32) {
+        let name = CString::new(name).unwrap();
+        unsafe { oidnSetDeviceInt(self.0, name.as_ptr(), value) };
+    }
+
+    fn set_bool(&self, name: &str, value: bool) {
+        let name = CString::new(name).unwrap();
+        unsafe { oidnSetDeviceBool(self.0, name.as_ptr(), value) };
+    }
+
+    fn get_int(&self, name: &str) -> i32 {
+        let name = CString::new(name).unwrap();
+        unsafe { oidnGetDeviceInt(self.0, name.as_ptr()) }
+    }
+
+    // a device buffer holding a copy of `data`
+    fn new_buffer(&self, data: &[f32]) -> OIDNBuffer {
+        let size = std::mem::size_of_val(data);
+        let buffer = unsafe { oidnNewBuffer(self.0, size) };
+        if buffer.is_null() {
+            panic!("couldn't allocate an oidn buffer: {}", self.get_error().err().unwrap_or_default());
+        }
+        unsafe { oidnWriteBuffer(buffer, 0, size, data.as_ptr() as *const c_void) };
+        buffer
+    }
+
+    fn get_bool(&self, name: &str) -> bool {
+        let name = CString::new(name).unwrap();
+        unsafe { oidnGetDeviceBool(self.0, name.as_ptr()) }
+    }
+
+    pub fn get_error(&self) -> Result<(), String> {
+        let mut message: *const c_char = ptr::null();
+        let error = unsafe { oidnGetDeviceError(self.0, &mut message) };
+        if error == OIDNError_OIDN_ERROR_NONE {
+            return Ok(());
+        }
+        let message = match message.is_null() {
+            true => String::new(),
+            false => unsafe { CStr::from_ptr(message) }.to_string_lossy().to_string(),
+        };
+        Err(format!("{} (error {})", message, error))
+    }
+}
+
+impl Drop for Device {
+    fn drop(&mut self) {
+        unsafe { oidnReleaseDevice(self.0) };
+    }
+}
+
+// a ray tracing ("RT") or lightmap ("RTLightmap") filter, the images are bound when it is executed
+pub struct Filter<'a> {
+    handle: OIDNFilter,
+    device: &'a Device,
+    bound: Vec<String>, // input images bound by the last execute
+}
+
+impl<'a> Filter<'a> {
+    pub fn new(device: &'a Device, kind: &str) -> Filter<'a> {
+        let kind = CString::new(kind).unwrap();
+        let handle = unsafe { oidnNewFilter(device.0, kind.as_ptr()) };
+        if handle.is_null() {
+            panic!("couldn't create an oidn filter");
+        }
+        Filter {
+            handle,
+            device,
+            bound: Vec::new(),
+        }
+    }
+
+    pub fn set_bool(&mut self, name: &str, value: bool) -> &mut Self {
+        let name = CString::new(name).unwrap();
+        unsafe { oidnSetFilterBool(self.handle, name.as_ptr(), value) };
+        self
+    }
+
+    pub fn set_int(&mut self, name: &str, value: i32) -> &mut Self {
+        let name = CString::new(name).unwrap();
+        unsafe { oidnSetFilterInt(self.handle, name.as_ptr(), value) };
+        self
+    }
+
+    pub fn set_float(&mut self, name: &str, value: f32) -> &mut Self {
+        let name = CString::new(name).unwrap();
+        unsafe { oidnSetFilterFloat(self.handle, name.as_ptr(), value) };
+        self
+    }
+
+    // custom trained weights instead of the built in ones, they have to stay alive as long as the filter
+    pub fn weights(&mut self, weights: &'a [u8]) -> &mut Self {
+        let name = CString::new("weights").unwrap();
+        unsafe {
+            // oidn only reads the weights
+            oidnSetSharedFilterData(self.handle, name.as_ptr(), weights.as_ptr() as *mut c_void, weights.len())
+        };
+        self
+    }
+
+    pub fn range(&mut self, range: Range) -> &mut Self {
+        self.set_bool("hdr", range == Range::Hdr)
+            .set_bool("srgb", range == Range::Srgb)
+    }
+
+    pub fn quality(&mut self, quality: Quality) -> &mut Self {
+        self.set_int("quality", quality.as_raw())
+    }
+
+    // denoise `output` in place, it is bound as the input image `output_input` (color for the beauty,
+    // albedo or normal when prefiltering), `inputs` are the other images, all of them RGB at width x height
+    pub fn execute(
+        &mut self,
+        output_input: &str,
+        output: &mut [f32],
+        inputs: &[(&str, &[f32])],
+        width: usize,
+        height: usize,
+    ) -> Result<(), String> {
+        let n_values = width * height * 3;
+        if output.len() != n_values || inputs.iter().any(|(_, data)| data.len() != n_values) {
+            panic!("invalid input image dimensions, the images don't have {}x{} RGB pixels", width, height);
+        }
+
+        // gpus that can't read host memory get copies in device buffers, the output is read back after executing
+        let shared = self.device.get_bool("systemMemorySupported");
+        let mut buffers: Vec<OIDNBuffer> = Vec::new();
+        if shared {
+            self.bind_shared(output_input, output.as_mut_ptr(), width, height);
+            self.bind_shared("output", output.as_mut_ptr(), width, height);
+            for (name, data) in inputs {
+                // oidn only reads the input images
+                self.bind_shared(name, data.as_ptr() as *mut f32, width, height);
+            }
+        } else {
+            // the output overwrites the input in the same buffer, oidn filters in place
+            buffers.push(self.device.new_buffer(output));
+            self.bind_buffer(output_input, buffers[0], width, height);
+            self.bind_buffer("output", buffers[0], width, height);
+            for (name, data) in inputs {
+                let buffer = self.device.new_buffer(data);
+                buffers.push(buffer);
+                self.bind_buffer(name, buffer, width, height);
+            }
+        }
+
+        // images bound by an earlier call would still be used otherwise
+        let mut bound: Vec<String> = vec![output_input.to_string()];
+        bound.extend(inputs.iter().map(|(name, _)| name.to_string()));
+        for name in &self.bound {
+            if !bound.contains(name) {
+                let name = CString::new(name.as_str()).unwrap();
+                unsafe { oidnUnsetFilterImage(self.handle, name.as_ptr()) };
+            }
+        }
+        self.bound = bound;
+
+        unsafe {
+            oidnCommitFilter(self.handle);
+            oidnExecuteFilter(self.handle);
+        }
+        if let Some(&output_buffer) = buffers.first() {
+            let size = std::mem::size_of_val(output);
+            unsafe { oidnReadBuffer(output_buffer, 0, size, output.as_mut_ptr() as *mut c_void) };
+        }
+        // the filter keeps its own reference to the buffers until they are replaced
+        for buffer in buffers {
+            unsafe { oidnReleaseBuffer(buffer) };
+        }
+        self.device.get_error()
+    }
+
+    fn bind_shared(&mut self, name: &str, data: *mut f32, width: usize, height: usize) {
+        let name = CString::new(name).unwrap();
+        unsafe {
+            oidnSetSharedFilterImage(
+                self.handle,
+                name.as_ptr(),
+                data as *mut c_void,
+                OIDNFormat_OIDN_FORMAT_FLOAT3,
+                width,
+                height,
+                0,
+                0,
+                0,
+            )
+        };
+    }
+
+    fn bind_buffer(&mut self, name: &str, buffer: OIDNBuffer, width: usize, height: usize) {
+        let name = CString::new(name).unwrap();
+        unsafe {
+            oidnSetFilterImage(
+                self.handle,
+                name.as_ptr(),
+                buffer,
+                OIDNFormat_OIDN_FORMAT_FLOAT3,
+                width,
+                height,
+                0,
+                0,
+                0,
+            )
+        };
+    }
+}
+
+impl Drop for Filter<'_> {
+    fn drop(&mut self) {
+        unsafe { oidnReleaseFilter(self.handle) };
+    }
+}
@@ -1,153 +1,635 @@
-use exr::prelude::RgbaImage as rgb_exr;
+use exr::meta::attribute::AttributeValue;
+use exr::prelude::{AnyChannels, FlatSamples, ImageAttributes, IntegerBounds, Layers, Levels};
 use hashbrown::HashMap;
 
+use crate::deep;
+
 #[derive(Clone, Debug)]
 pub enum BufferType {
     RGB,
     RGBA,
+    Mono, // single channel passes like AO, shadow or density, denoised as grey RGB
+    Dual, // two channel passes, denoised as RGB with an empty blue channel
     Other,
 }
 
+/// Sample type of the denoised channels in the written file.
+#[derive(Clone, Copy, Debug)]
+pub enum Precision {
+    Half,
+    Float,
+    Source, // same as the channel in the source exr
+}
+
+impl Precision {
+    pub fn from_name(name: &str) -> Precision {
+        match name {
+            "half" => Precision::Half,
+            "float" => Precision::Float,
+            "same" => Precision::Source,
+            _ => panic!("unknown precision: {}", name),
+        }
+    }
+}
+
+// None keeps the compression of the source exr
+pub fn compression_from_name(name: &str) -> Option<exr::prelude::Compression> {
+    use exr::prelude::Compression;
+    match name {
+        "same" => None,
+        "none" => Some(Compression::Uncompressed),
+        "rle" => Some(Compression::RLE),
+        "zips" => Some(Compression::ZIP1),
+        "zip" => Some(Compression::ZIP16),
+        "piz" => Some(Compression::PIZ),
+        "pxr24" => Some(Compression::PXR24),
+        "b44" => Some(Compression::B44),
+        "b44a" => Some(Compression::B44A),
+        _ => panic!("unknown compression: {}", name),
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SaveOptions {
+    pub precision: Precision,
+    pub compression: Option<exr::prelude::Compression>,
+}
+
+// channel name suffixes a buffer can be built from, in interleaving order, alpha is optional
+const CHANNEL_SETS: [[&str; 4]; 4] = [
+    ["R", "G", "B", "A"],
+    ["r", "g", "b", "a"],
+    ["X", "Y", "Z", ""],
+    ["x", "y", "z", ""],
+];
+
+// layer names renderers use for the denoising passes, compared without case, spaces and underscores, in order of preference
+pub const ALBEDO_AOV_NAMES: [&str; 6] = [
+    "denoising albedo", // blender
+    "denoise_albedo",
+    "denoising_albedo",
+    "albedo", // arnold, karma, renderman
+    "diffuse_albedo",
+    "VRayDiffuseFilter", // v-ray
+];
+pub const NORMAL_AOV_NAMES: [&str; 8] = [
+    "denoising normal", // blender
+    "denoise_normal",
+    "denoising_normal",
+    "normal", // renderman
+    "normals",
+    "N", // arnold, karma
+    "bumpNormals",
+    "VRayBumpNormals", // v-ray
+];
+
+// data passes that are RGB(A) but hold positions, motion or ids, `-e all` leaves them out
+pub const DATA_LAYER_NAMES: [&str; 10] = [
+    "P",
+    "Pref",
+    "position",
+    "world_position",
+    "motion",
+    "motionvector",
+    "motionvectors",
+    "motion_vector",
+    "velocity",
+    "uv",
+];
+
+/// Channels of a part sharing a name prefix, e.g. diffuse.R, diffuse.G, diffuse.B -> diffuse.
+pub struct ChannelGroup {
+    pub prefix: String,
+    pub buffer_type: BufferType,
+    pub channels: Vec<usize>, // every channel of the group, indices into the channel list of the part
+    pub mapped: Vec<usize>,   // the channels interleaved into the buffer, in order
+}
+
+// group the channel names of a part by their prefix and pick the first channel set each group fully contains,
+// otherwise the channels are taken as they come
+pub fn group_channels(names: &[String]) -> Vec<ChannelGroup> {
+    let mut groups: Vec<ChannelGroup> = Vec::new();
+    for (i, name) in names.iter().enumerate() {
+        let prefix = name.rsplit_once('.').map_or("", |(prefix, _)| prefix);
+        match groups.iter_mut().find(|group| group.prefix == prefix) {
+            Some(group) => group.channels.push(i),
+            None => groups.push(ChannelGroup {
+                prefix: prefix.to_string(),
+                buffer_type: BufferType::Other,
+                channels: vec![i],
+                mapped: Vec::new(),
+            }),
+        }
+    }
+
+    for group in &mut groups {
+        let suffix = |i: usize| names[i][group.prefix.len()..].trim_start_matches('.');
+        group.buffer_type = match group.channels.len() {
+            1 => BufferType::Mono,
+            2 => BufferType::Dual,
+            _ => BufferType::Other,
+        };
+        group.mapped = group.channels.clone();
+        for set in CHANNEL_SETS {
+            let find = |wanted: &str| group.channels.iter().copied().find(|i| suffix(*i) == wanted);
+            if let (Some(x), Some(y), Some(z)) = (find(set[0]), find(set[1]), find(set[2])) {
+                group.mapped = vec![x, y, z];
+                group.buffer_type = BufferType::RGB;
+                if let Some(a) = find(set[3]).filter(|_| !set[3].is_empty()) {
+                    group.mapped.push(a);
+                    group.buffer_type = BufferType::RGBA;
+                }
+                break;
+            }
+        }
+    }
+    groups
+}
+
+/// Which channels of the source exr a buffer was interleaved from.
+#[derive(Clone, Debug)]
+pub struct ChannelMap {
+    pub part: usize,                // index into FloatImage.layers
+    pub channels: Vec<String>,      // full source channel names, in the order they are interleaved in the buffer
+    pub data_window: IntegerBounds, // position and resolution of the part, parts of one file can differ
+}
+
+impl ChannelMap {
+    pub fn describe(&self) -> String {
+        format!(
+            "part {} {}x{} <- {}",
+            self.part,
+            self.data_window.size.x(),
+            self.data_window.size.y(),
+            self.channels.join(", ")
+        )
+    }
+}
+
 #[derive(Clone)]
 pub struct FloatImage {
-    pub width: usize,
-    pub height: usize,
     pub buffers: HashMap<String, (BufferType, Vec<f32>)>,
+    pub channel_maps: HashMap<String, ChannelMap>,
+    pub level: usize, // resolution level the buffers hold, 0 is the full resolution
+    pub deep: bool,   // flattened from a deep exr
+    pub layers: Layers<AnyChannels<Levels<FlatSamples>>>, // the layers as read from the exr, written back on save
+    pub attributes: ImageAttributes, // header attributes shared by all layers (display window, chromaticities, ..)
 }
 impl FloatImage {
-    pub fn new(
-        width: usize,
-        height: usize,
-        buffers: HashMap<String, (BufferType, Vec<f32>)>,
-    ) -> FloatImage {
-        FloatImage {
-            width: width,
-            height: height,
-            buffers: buffers,
-        }
-    }
-
     pub fn from_exr(exr_path: String) -> FloatImage {
         use exr::prelude::*;
         let path = exr_path.as_str();
+
+        let is_deep = deep::is_deep(path);
+        let image = if is_deep {
+            let (attributes, layers) = deep::read_flattened(path);
+            Image::from_layers(attributes, layers)
+        } else {
+            read()
+                .no_deep_data()
+                .all_resolution_levels()
+                .all_channels()
+                .all_layers()
+                .all_attributes()
+                .from_file(path)
+                .unwrap()
+        };
+
+        let mut float_image = FloatImage {
+            buffers: HashMap::new(),
+            channel_maps: HashMap::new(),
+            level: 0,
+            deep: is_deep,
+            layers: image.layer_data,
+            attributes: image.attributes,
+        };
+        float_image.load_level(0);
+        float_image
+    }
+
+    // rough memory use in bytes of the samples and buffers, used to cap the frames kept in memory
+    pub fn memory_size(&self) -> usize {
+        let samples: usize = self
+            .layers
+            .iter()
+            .flat_map(|layer| layer.channel_data.list.iter())
+            .flat_map(|channel| channel.sample_data.levels_as_slice().iter())
+            .map(|samples| match samples {
+                FlatSamples::F16(values) => values.len() * 2,
+                FlatSamples::F32(values) => values.len() * 4,
+                FlatSamples::U32(values) => values.len() * 4,
+            })
+            .sum();
+        let buffers: usize = self.buffers.values().map(|(_, data)| data.len() * 4).sum();
+        samples + buffers
+    }
+
+    // memory_size of the exr before it is read, from its headers. 0 when they can't be read, reading fails then anyway
+    pub fn estimate_memory_size(exr_path: &str) -> usize {
+        use exr::meta::attribute::LevelMode;
+        use exr::meta::{mip_map_levels, rip_map_levels, BlockDescription, MetaData};
+        let meta = match MetaData::read_from_file(exr_path, false) {
+            Ok(meta) => meta,
+            Err(_) => return 0,
+        };
+        meta.headers
+            .iter()
+            .map(|header| {
+                let size = header.layer_size;
+                let pixels: usize = match header.blocks {
+                    BlockDescription::Tiles(tiles) => match tiles.level_mode {
+                        LevelMode::Singular => size.area(),
+                        LevelMode::MipMap => mip_map_levels(tiles.rounding_mode, size).map(|(_, s)| s.area()).sum(),
+                        LevelMode::RipMap => rip_map_levels(tiles.rounding_mode, size).map(|(_, s)| s.area()).sum(),
+                    },
+                    BlockDescription::ScanLines => size.area(),
+                };
+                let channels = &header.channels.list;
+                let sample_bytes: usize = channels.iter().map(|channel| channel.sample_type.bytes_per_sample()).sum();
+                // the samples of every level and the f32 buffers of the full resolution
+                pixels * sample_bytes + size.area() * channels.len() * 4
+            })
+            .sum()
+    }
+
+    // number of resolution levels (mip or rip maps) of the part the layer is in, 1 for a regular image
+    pub fn level_count(&self, layer_name: &str) -> usize {
+        let channel_map = self
+            .channel_maps
+            .get(layer_name)
+            .expect("couldn't find layer in the source exr");
+        let layer = &self.layers[channel_map.part];
+        layer.channel_data.list[0].sample_data.levels_as_slice().len()
+    }
+
+    // fill the buffers from one resolution level, parts that don't have this level are left out
+    pub fn load_level(&mut self, level: usize) {
+        use exr::prelude::*;
         let mut out_buffers: HashMap<String, (BufferType, Vec<f32>)> = HashMap::new();
+        let mut out_channel_maps: HashMap<String, ChannelMap> = HashMap::new();
 
-        let image = read()
-            .no_deep_data()
-            .largest_resolution_level()
-            .all_channels()
-            .all_layers()
-            .all_attributes()
-            .from_file(path)
-            .unwrap();
-
-        let size = image.layer_data[0].size;
-        //println!("size: {:?}", size);
-        let mut n_pixels = 0 as usize;
-
-        for (layer_index, layer) in image.layer_data.iter().enumerate() {
-            let layer_name = layer
-                .attributes
-                .layer_name
-                .as_ref()
-                .map_or(String::from("main_layer"), Text::to_string);
-            //println!("layer: {:?}",layer_name);
-
-            let mut channels: Vec<Vec<f32>> = Vec::new(); // to temporally store each channel as its own vector
-
-            for channel in &layer.channel_data.list {
-                //println!("channel: {:?}",channel.name );
-                let channel_values: Vec<f32> = channel.sample_data.values_as_f32().collect();
-                n_pixels = channel_values.len();
-                channels.push(channel_values);
-            }
+        for (part_index, layer) in self.layers.iter().enumerate() {
+            let part_name = layer.attributes.layer_name.as_ref().map(Text::to_string);
+            let level_size = match layer
+                .levels_with_resolution(&layer.channel_data.list[0].sample_data)
+                .nth(level)
+            {
+                Some((_, size)) => size,
+                None => continue,
+            };
+
+            let names: Vec<String> = layer.channel_data.list.iter().map(|channel| channel.name.to_string()).collect();
+            for group in group_channels(&names) {
+                let layer_name = layer_key(part_name.as_deref(), &group.prefix);
+                let mapped: Vec<&AnyChannel<Levels<FlatSamples>>> =
+                    group.mapped.iter().map(|i| &layer.channel_data.list[*i]).collect();
+
+                let channels: Vec<Vec<f32>> = mapped
+                    .iter()
+                    .map(|channel| channel.sample_data.levels_as_slice()[level].values_as_f32().collect())
+                    .collect();
+                let n_pixels = level_size.area();
 
-            // merge the channels into one vec in R,G,B,A,R,G,B,A,R,G,B,A format
-            let mut out_buffer: Vec<f32> = Vec::new();
-            for pixel_index in 0..n_pixels {
-                for ch_data in channels.iter().rev() {
-                    out_buffer.push(ch_data[pixel_index])
+                // merge the channels into one vec in R,G,B,A,R,G,B,A,R,G,B,A format
+                let mut out_buffer: Vec<f32> = Vec::with_capacity(n_pixels * channels.len());
+                for pixel_index in 0..n_pixels {
+                    for ch_data in &channels {
+                        out_buffer.push(ch_data[pixel_index])
+                    }
                 }
+
+                out_channel_maps.insert(
+                    layer_name.clone(),
+                    ChannelMap {
+                        part: part_index,
+                        channels: mapped.iter().map(|channel| channel.name.to_string()).collect(),
+                        data_window: IntegerBounds::new(layer.attributes.layer_position, level_size),
+                    },
+                );
+                out_buffers.insert(layer_name, (group.buffer_type, out_buffer));
             }
+        }
+
+        if out_buffers.is_empty() {
+            panic!("resolution level {} not found in the exr", level);
+        }
 
-            let buffer_type = match (out_buffer.len() / (size.x() * size.y())) {
-                3 => BufferType::RGB,
-                4 => BufferType::RGBA,
-                _ => BufferType::Other,
+        self.buffers = out_buffers;
+        self.channel_maps = out_channel_maps;
+        self.level = level;
+    }
+
+    // resolve the --layerbeauty value: a comma separated list of layer names, globs or "all" for every RGB(A) layer
+    // that isn't a denoising pass itself
+    pub fn select_layers(&self, selection: &str) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for item in selection.split(',').map(str::trim) {
+            let found: Vec<String> = if item == "all" {
+                let aov_names: Vec<String> = ALBEDO_AOV_NAMES
+                    .iter()
+                    .chain(NORMAL_AOV_NAMES.iter())
+                    .chain(DATA_LAYER_NAMES.iter())
+                    .map(|name| normalize_aov_name(name))
+                    .collect();
+                self.buffers
+                    .iter()
+                    .filter(|(key, (buffer_type, _))| {
+                        let last = key.rsplit_once('.').map_or(key.as_str(), |(_, last)| last);
+                        matches!(buffer_type, BufferType::RGB | BufferType::RGBA)
+                            && !aov_names.contains(&normalize_aov_name(last))
+                            && !self.is_data_layer(key)
+                    })
+                    .map(|(key, _)| key.clone())
+                    .collect()
+            } else if item.contains(['*', '?', '[']) {
+                let pattern = glob::Pattern::new(item).expect("invalid layer pattern");
+                self.buffers.keys().filter(|key| pattern.matches(key)).cloned().collect()
+            } else if self.buffers.contains_key(item) {
+                vec![item.to_string()]
+            } else {
+                panic!("layer not found in beauty: {}", item);
             };
 
-            out_buffers.insert(layer_name.clone(), (buffer_type, out_buffer));
+            if found.is_empty() {
+                panic!("no layer in the beauty matches {}", item);
+            }
+            for name in found {
+                if !names.contains(&name) {
+                    names.push(name);
+                }
+            }
         }
+        names.sort();
+        names
+    }
+
+    // layers whose pixels are data rather than color: cryptomattes (by name or the cryptomatte/*/name header
+    // attributes) and layers picked from X/Y/Z channels (positions, vectors)
+    fn is_data_layer(&self, key: &str) -> bool {
+        let channel_map = &self.channel_maps[key];
+        let last = key.rsplit_once('.').map_or(key, |(_, last)| last);
 
-        FloatImage {
-            width: size.0,
-            height: size.1,
-            buffers: out_buffers,
+        if last.to_lowercase().starts_with("crypto") {
+            return true;
         }
+        // cryptomatte/<id>/name holds the layer name, e.g. "ViewLayer.CryptoObject" for its 00, 01, .. layers
+        let layer_attributes = &self.layers[channel_map.part].attributes.other;
+        for (name, value) in self.attributes.other.iter().chain(layer_attributes.iter()) {
+            let name = name.to_string();
+            if let AttributeValue::Text(text) = value {
+                let text = text.to_string();
+                let is_name = name.starts_with("cryptomatte/") && name.ends_with("/name");
+                if is_name && (key.starts_with(&text) || last.starts_with(&text)) {
+                    return true;
+                }
+            }
+        }
+
+        channel_map.channels.iter().any(|channel| {
+            let suffix = channel.rsplit_once('.').map_or(channel.as_str(), |(_, suffix)| suffix);
+            matches!(suffix, "X" | "Y" | "Z" | "x" | "y" | "z")
+        })
     }
 
-    pub fn save_to_file(&self, out_file: String, out_layer: &String) {
-        let (layer_type, layer_data) = self
-            .buffers
-            .get(out_layer)
-            .expect("couldn't find layer in multi_image");
+    // find a denoising pass stored next to the beauty layer, e.g. "ViewLayer.Denoising Albedo" for "ViewLayer.Combined",
+    // layers under the same prefix (part or view layer) as the beauty are preferred
+    pub fn find_aov(&self, beauty_layer: &str, names: &[&str]) -> Option<String> {
+        let beauty_prefix = beauty_layer.rsplit_once('.').map_or("", |(prefix, _)| prefix);
 
-        let get_pixel_rgb = |x: usize, y: usize| {
-            let pixel_i = x as usize + (y as usize * *&self.width as usize);
-            (
-                layer_data[pixel_i * 3],
-                layer_data[pixel_i * 3 + 1],
-                layer_data[pixel_i * 3 + 2],
-            )
-        };
+        for name in names {
+            let mut found: Vec<&String> = self
+                .buffers
+                .iter()
+                .filter(|(key, (buffer_type, _))| {
+                    let last = key.rsplit_once('.').map_or(key.as_str(), |(_, last)| last);
+                    key.as_str() != beauty_layer
+                        && normalize_aov_name(last) == normalize_aov_name(name)
+                        && matches!(buffer_type, BufferType::RGB | BufferType::RGBA)
+                })
+                .map(|(key, _)| key)
+                .collect();
+            found.sort_by_key(|key| {
+                let prefix = key.rsplit_once('.').map_or("", |(prefix, _)| prefix);
+                (prefix != beauty_prefix, key.len(), key.to_string())
+            });
+            if let Some(key) = found.first() {
+                return Some(key.to_string());
+            }
+        }
+        None
+    }
+
+    // add R, G, B channels for a new layer to the part of `template_layer`, with the same resolution levels,
+    // the buffer is filled in later and written with store_layer
+    pub fn add_rgb_layer(&mut self, name: &str, template_layer: &str) -> String {
+        use exr::prelude::*;
+        let template = self
+            .channel_maps
+            .get(template_layer)
+            .expect("couldn't find layer in the source exr")
+            .clone();
+        let layer = &mut self.layers[template.part];
+        let part_name = layer.attributes.layer_name.as_ref().map(Text::to_string);
 
-        let get_pixel_rgba = |x: usize, y: usize| {
-            let pixel_i = x as usize + (y as usize * *&self.width as usize);
-            (
-                layer_data[pixel_i * 4],
-                layer_data[pixel_i * 4 + 1],
-                layer_data[pixel_i * 4 + 2],
-                layer_data[pixel_i * 4 + 3],
-            )
+        // the channel prefix is relative to the part, a part.name key doesn't need the part in the channel names
+        let prefix = match &part_name {
+            Some(part) => name.strip_prefix(&format!("{}.", part)).unwrap_or(name),
+            None => name,
         };
+        let key = layer_key(part_name.as_deref(), prefix);
+        if self.channel_maps.contains_key(&key) {
+            panic!("layer {} already exists in the beauty", key);
+        }
 
-        // write a file without alpha and 32-bit float precision per channel
-        match layer_type {
-            BufferType::RGB => {
-                exr::prelude::write_rgb_file(
-                    &out_file,
-                    *&self.width as usize,
-                    *&self.height as usize, // write an image with this resolution
-                    |x, y| {
-                        (
-                            // generate an f32 rgb color for each of the  pixels
-                            get_pixel_rgb(x, y)
-                        )
-                    },
-                )
-                .unwrap();
+        let mut levels = layer.channel_data.list[0].sample_data.clone();
+        for samples in levels.levels_as_slice_mut() {
+            *samples = FlatSamples::F32(vec![0.0; samples.len()]);
+        }
+        let channels: Vec<String> = ["R", "G", "B"].iter().map(|c| format!("{}.{}", prefix, c)).collect();
+        let mut list = layer.channel_data.list.clone();
+        for channel_name in &channels {
+            list.push(AnyChannel::new(channel_name.as_str(), levels.clone()));
+        }
+        layer.channel_data = AnyChannels::sort(list);
+
+        self.channel_maps.insert(
+            key.clone(),
+            ChannelMap {
+                part: template.part,
+                channels,
+                data_window: template.data_window,
+            },
+        );
+        self.buffers.insert(
+            key.clone(),
+            (BufferType::RGB, vec![0.0; template.data_window.size.area() * 3]),
+        );
+        key
+    }
+
+    // add up the RGB of the layers, e.g. denoised light path AOVs back into a beauty, alpha is left out
+    pub fn sum_layers(&self, layer_names: &[String]) -> Vec<f32> {
+        let window = self.channel_maps[&layer_names[0]].data_window;
+        let mut sum = vec![0.0; window.size.area() * 3];
+        for layer_name in layer_names {
+            if self.channel_maps[layer_name].data_window.size != window.size {
+                panic!("layer {} doesn't have the resolution of {}, can't add them up", layer_name, layer_names[0]);
             }
-            BufferType::RGBA => {
-                exr::prelude::write_rgba_file(
-                    &out_file,
-                    *&self.width as usize,
-                    *&self.height as usize, // write an image with this resolution
-                    |x, y| {
-                        (
-                            // generate an f32 rgb color for each of the  pixels
-                            get_pixel_rgba(x, y)
-                        )
-                    },
-                )
-                .unwrap();
+            let (buffer_type, data) = &self.buffers[layer_name];
+            let rgb = match buffer_type {
+                BufferType::RGB => data.clone(),
+                BufferType::RGBA => strip_alpha(data.clone()).0,
+                BufferType::Mono => mono_to_rgb(data.clone()),
+                _ => panic!("layer {} is not an RGB layer, can't add it up", layer_name),
+            };
+            for (total, value) in sum.iter_mut().zip(rgb) {
+                *total += value;
             }
-            _ => {}
         }
+        sum
+    }
+
+    // write the buffer of a layer back into the source layers, e.g. before moving on to another level
+    pub fn store_layer(&mut self, layer_name: &str, precision: Precision) {
+        let (_layer_type, layer_data) = self
+            .buffers
+            .get(layer_name)
+            .expect("couldn't find layer in multi_image");
+        let channel_map = self
+            .channel_maps
+            .get(layer_name)
+            .expect("couldn't find layer in the source exr");
+
+        write_buffer_to_layers(&mut self.layers, channel_map, layer_data, self.level, precision);
+    }
+
+    // write the layers with the stored buffers (see store_layer), everything else stays as it was read
+    pub fn save_to_file(&self, out_file: String, options: &SaveOptions) {
+        use exr::prelude::*;
+
+        let mut layers = self.layers.clone();
+        if let Some(compression) = options.compression {
+            for layer in layers.iter_mut() {
+                layer.encoding.compression = compression;
+            }
+        }
+
+        // the layers carry their own attributes and encoding (data window origin, compression, line order,
+        // tiles and mip/rip levels), the image attributes hold the rest of the original header including the display window
+        let image = Image::from_layers(self.attributes.clone(), layers);
+        image.write().to_file(&out_file).unwrap();
 
         println!("created file {:?}", out_file);
     }
 }
 
+// split the R,G,B,A,R,G,B,A,.. buffer back into the channels it was read from
+fn write_buffer_to_layers(
+    layers: &mut Layers<AnyChannels<Levels<FlatSamples>>>,
+    channel_map: &ChannelMap,
+    layer_data: &[f32],
+    level: usize,
+    precision: Precision,
+) {
+    let layer = &mut layers[channel_map.part];
+
+    let n_channels = channel_map.channels.len();
+    for (component, channel_name) in channel_map.channels.iter().enumerate() {
+        let channel = layer
+            .channel_data
+            .list
+            .iter_mut()
+            .find(|channel| channel.name.to_string() == *channel_name)
+            .expect("couldn't find channel in the source exr");
+
+        // all levels of a channel need the same sample type, so the other levels are converted as well
+        for (level_index, samples) in channel.sample_data.levels_as_slice_mut().iter_mut().enumerate() {
+            if level_index == level {
+                let values = layer_data.iter().skip(component).step_by(n_channels).copied();
+                *samples = to_samples(values, precision, samples);
+            } else if !matches!(precision, Precision::Source) {
+                *samples = to_samples(samples.values_as_f32(), precision, samples);
+            }
+        }
+    }
+}
+
+fn to_samples(values: impl Iterator<Item = f32>, precision: Precision, source: &FlatSamples) -> FlatSamples {
+    match (precision, source) {
+        (Precision::Half, _) | (Precision::Source, FlatSamples::F16(_)) => {
+            FlatSamples::F16(values.map(to_half).collect())
+        }
+        (Precision::Float, _) | (Precision::Source, FlatSamples::F32(_)) => FlatSamples::F32(values.collect()),
+        (Precision::Source, FlatSamples::U32(_)) => FlatSamples::U32(values.map(|v| v as u32).collect()),
+    }
+}
+
+// round to the nearest half, clamping to the largest finite half so bright pixels don't turn into infinity
+fn to_half(value: f32) -> exr::prelude::f16 {
+    use exr::prelude::f16;
+    if value.is_finite() {
+        f16::from_f32(value.clamp(f16::MIN.to_f32(), f16::MAX.to_f32()))
+    } else {
+        f16::from_f32(value)
+    }
+}
+
+fn normalize_aov_name(name: &str) -> String {
+    name.replace([' ', '_'], "").to_lowercase()
+}
+
+// the name a channel group is stored under in FloatImage.buffers
+pub fn layer_key(part_name: Option<&str>, prefix: &str) -> String {
+    match (part_name, prefix) {
+        (None, "") => String::from("main_layer"),
+        (None, prefix) => prefix.to_string(),
+        (Some(part), "") => part.to_string(),
+        (Some(part), prefix) if prefix == part || prefix.starts_with(&format!("{}.", part)) => {
+            prefix.to_string()
+        }
+        (Some(part), prefix) => format!("{}.{}", part, prefix),
+    }
+}
+
+// place a buffer read in the `from` window into the `to` window, pixels outside of `from` are left black
+pub fn fit_to_window(data: &[f32], from: IntegerBounds, to: IntegerBounds) -> Vec<f32> {
+    let n_channels = data.len() / from.size.area();
+    let mut out = vec![0.0; to.size.area() * n_channels];
+
+    for y in 0..to.size.y() {
+        for x in 0..to.size.x() {
+            let source_x = to.position.x() + x as i32 - from.position.x();
+            let source_y = to.position.y() + y as i32 - from.position.y();
+            if source_x < 0
+                || source_y < 0
+                || source_x as usize >= from.size.x()
+                || source_y as usize >= from.size.y()
+            {
+                continue;
+            }
+
+            let source_i = (source_x as usize + source_y as usize * from.size.x()) * n_channels;
+            let out_i = (x + y * to.size.x()) * n_channels;
+            out[out_i..out_i + n_channels].copy_from_slice(&data[source_i..source_i + n_channels]);
+        }
+    }
+    out
+}
+
+pub fn intersect(a: IntegerBounds, b: IntegerBounds) -> Option<IntegerBounds> {
+    let start_x = a.position.x().max(b.position.x());
+    let start_y = a.position.y().max(b.position.y());
+    let end_x = a.end().x().min(b.end().x());
+    let end_y = a.end().y().min(b.end().y());
+    if start_x >= end_x || start_y >= end_y {
+        return None;
+    }
+    Some(IntegerBounds::new(
+        (start_x, start_y),
+        ((end_x - start_x) as usize, (end_y - start_y) as usize),
+    ))
+}
+
 pub fn strip_alpha(rgba_data: Vec<f32>) -> (Vec<f32>, Vec<f32>) {
     let mut rgb_data = Vec::new();
     let mut a_data = Vec::new();
@@ -172,3 +654,59 @@ pub fn add_alpha(rgb_data: Vec<f32>, a_data: Vec<f32>) -> Vec<f32> {
     }
     rgba_data
 }
+
+// oidn only takes 3 channels, so single and two channel buffers are expanded to RGB and collapsed back after denoising
+pub fn mono_to_rgb(mono_data: Vec<f32>) -> Vec<f32> {
+    let mut rgb_data = Vec::with_capacity(mono_data.len() * 3);
+    for value in mono_data {
+        rgb_data.push(value);
+        rgb_data.push(value);
+        rgb_data.push(value);
+    }
+    rgb_data
+}
+
+pub fn rgb_to_mono(rgb_data: Vec<f32>) -> Vec<f32> {
+    rgb_data.chunks(3).map(|rgb| (rgb[0] + rgb[1] + rgb[2]) / 3.0).collect()
+}
+
+pub fn dual_to_rgb(dual_data: Vec<f32>) -> Vec<f32> {
+    let mut rgb_data = Vec::with_capacity(dual_data.len() / 2 * 3);
+    for dual in dual_data.chunks(2) {
+        rgb_data.push(dual[0]);
+        rgb_data.push(dual[1]);
+        rgb_data.push(0.0);
+    }
+    rgb_data
+}
+
+pub fn rgb_to_dual(rgb_data: Vec<f32>) -> Vec<f32> {
+    let mut dual_data = Vec::with_capacity(rgb_data.len() / 3 * 2);
+    for rgb in rgb_data.chunks(3) {
+        dual_data.push(rgb[0]);
+        dual_data.push(rgb[1]);
+    }
+    dual_data
+}
+
+// mean and largest absolute difference per RGB channel between an original buffer and an RGB buffer
+pub fn residual(original: &(BufferType, Vec<f32>), rgb_data: &[f32]) -> ([f32; 3], [f32; 3]) {
+    let original_rgb = match original.0 {
+        BufferType::RGB => original.1.clone(),
+        BufferType::RGBA => strip_alpha(original.1.clone()).0,
+        _ => panic!("the residual needs an RGB layer"),
+    };
+    if original_rgb.len() != rgb_data.len() {
+        panic!("the residual layer doesn't have the resolution of the recombined layers");
+    }
+
+    let mut mean = [0.0; 3];
+    let mut max = [0.0f32; 3];
+    for (i, (a, b)) in original_rgb.iter().zip(rgb_data).enumerate() {
+        let difference = a - b;
+        mean[i % 3] += difference;
+        max[i % 3] = max[i % 3].max(difference.abs());
+    }
+    let n_pixels = (rgb_data.len() / 3).max(1) as f32;
+    (mean.map(|m| m / n_pixels), max)
+}
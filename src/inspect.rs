@@ -0,0 +1,232 @@
+// `inspect` prints the layers of an exr keyed the same way as --layerbeauty/--layeralbedo/--layernormal expect them
+
+use exr::meta::attribute::{AttributeValue, LevelMode, SampleType};
+use exr::meta::header::Header;
+use exr::meta::{mip_map_levels, rip_map_levels, BlockDescription, MetaData};
+use exr::prelude::{IntegerBounds, Text};
+use std::collections::HashMap;
+
+use crate::image::{group_channels, layer_key};
+
+struct LayerInfo {
+    name: String,
+    part: usize,
+    buffer_type: String,
+    channels: Vec<(String, &'static str, bool)>, // name, sample type and whether it gets denoised
+    data_window: IntegerBounds,
+    levels: usize,
+    compression: String,
+    attributes: Vec<(String, String)>,
+}
+
+// only the headers are read, the pixels of large files aren't decoded just to list them
+pub fn print_exr_info(path: &str, json: bool) {
+    let meta = MetaData::read_from_file(path, false).expect("couldn't read the exr header");
+
+    let mut infos: Vec<LayerInfo> = Vec::new();
+    for (part, header) in meta.headers.iter().enumerate() {
+        let part_name = header.own_attributes.layer_name.as_ref().map(Text::to_string);
+        let names: Vec<String> = header.channels.list.iter().map(|channel| channel.name.to_string()).collect();
+
+        for group in group_channels(&names) {
+            // every channel of the group, including the ones that are not picked for denoising
+            let channels = group
+                .channels
+                .iter()
+                .map(|i| {
+                    let sample_type = match header.channels.list[*i].sample_type {
+                        SampleType::F16 => "half",
+                        SampleType::F32 => "float",
+                        SampleType::U32 => "uint",
+                    };
+                    (names[*i].clone(), sample_type, group.mapped.contains(i))
+                })
+                .collect();
+
+            infos.push(LayerInfo {
+                name: layer_key(part_name.as_deref(), &group.prefix),
+                part,
+                buffer_type: format!("{:?}", group.buffer_type),
+                channels,
+                data_window: IntegerBounds::new(header.own_attributes.layer_position, header.layer_size),
+                levels: level_count(header),
+                compression: format!("{:?}", header.compression),
+                attributes: sorted_attributes(&header.own_attributes.other),
+            });
+        }
+    }
+    infos.sort_by(|a, b| (a.part, &a.name).cmp(&(b.part, &b.name)));
+
+    let deep = meta.headers.iter().any(|header| header.deep);
+    let display_window = meta.headers[0].shared_attributes.display_window;
+    let file_attributes = sorted_attributes(&meta.headers[0].shared_attributes.other);
+
+    if json {
+        print_json(path, deep, display_window, &file_attributes, &infos);
+    } else {
+        print_text(path, deep, display_window, &file_attributes, &infos);
+    }
+}
+
+// resolution levels of a part, rip maps count every combination of x and y level
+fn level_count(header: &Header) -> usize {
+    match header.blocks {
+        BlockDescription::Tiles(tiles) => match tiles.level_mode {
+            LevelMode::Singular => 1,
+            LevelMode::MipMap => mip_map_levels(tiles.rounding_mode, header.layer_size).count(),
+            LevelMode::RipMap => rip_map_levels(tiles.rounding_mode, header.layer_size).count(),
+        },
+        BlockDescription::ScanLines => 1,
+    }
+}
+
+fn print_text(
+    path: &str,
+    deep: bool,
+    display_window: IntegerBounds,
+    file_attributes: &[(String, String)],
+    infos: &[LayerInfo],
+) {
+    println!("file: {}{}", path, if deep { " (deep, flattened)" } else { "" });
+    println!("display window: {}", window_text(display_window));
+    for (name, value) in file_attributes {
+        println!("  {} = {}", name, value);
+    }
+
+    for info in infos {
+        println!();
+        println!("layer: {}", info.name);
+        println!("  part: {}", info.part);
+        println!("  type: {}", info.buffer_type);
+        println!("  resolution: {}x{}", info.data_window.size.x(), info.data_window.size.y());
+        println!("  data window: {}", window_text(info.data_window));
+        println!("  levels: {}", info.levels);
+        println!("  compression: {}", info.compression);
+        let channels: Vec<String> = info
+            .channels
+            .iter()
+            .map(|(name, sample_type, denoised)| match denoised {
+                true => format!("{} ({})", name, sample_type),
+                false => format!("{} ({}, kept)", name, sample_type),
+            })
+            .collect();
+        println!("  channels: {}", channels.join(", "));
+        for (name, value) in &info.attributes {
+            println!("  {} = {}", name, value);
+        }
+    }
+}
+
+fn print_json(
+    path: &str,
+    deep: bool,
+    display_window: IntegerBounds,
+    file_attributes: &[(String, String)],
+    infos: &[LayerInfo],
+) {
+    let layers: Vec<String> = infos
+        .iter()
+        .map(|info| {
+            let channels: Vec<String> = info
+                .channels
+                .iter()
+                .map(|(name, sample_type, denoised)| {
+                    format!(
+                        "{{\"name\": {}, \"type\": {}, \"denoised\": {}}}",
+                        json_string(name),
+                        json_string(sample_type),
+                        denoised
+                    )
+                })
+                .collect();
+            format!(
+                "{{\"name\": {}, \"part\": {}, \"type\": {}, \"width\": {}, \"height\": {}, \"data_window\": {}, \"levels\": {}, \"compression\": {}, \"channels\": [{}], \"attributes\": {}}}",
+                json_string(&info.name),
+                info.part,
+                json_string(&info.buffer_type),
+                info.data_window.size.x(),
+                info.data_window.size.y(),
+                window_json(info.data_window),
+                info.levels,
+                json_string(&info.compression),
+                channels.join(", "),
+                attributes_json(&info.attributes)
+            )
+        })
+        .collect();
+
+    println!(
+        "{{\"file\": {}, \"deep\": {}, \"display_window\": {}, \"attributes\": {}, \"layers\": [{}]}}",
+        json_string(path),
+        deep,
+        window_json(display_window),
+        attributes_json(file_attributes),
+        layers.join(", ")
+    );
+}
+
+fn sorted_attributes(attributes: &HashMap<Text, AttributeValue>) -> Vec<(String, String)> {
+    let mut out: Vec<(String, String)> = attributes
+        .iter()
+        .map(|(name, value)| (name.to_string(), attribute_text(value)))
+        .collect();
+    out.sort();
+    out
+}
+
+fn attribute_text(value: &AttributeValue) -> String {
+    match value {
+        AttributeValue::Text(text) => text.to_string(),
+        AttributeValue::TextVector(texts) => texts.iter().map(Text::to_string).collect::<Vec<_>>().join(", "),
+        AttributeValue::F64(value) => value.to_string(),
+        AttributeValue::F32(value) => value.to_string(),
+        AttributeValue::I32(value) => value.to_string(),
+        AttributeValue::Custom { kind, bytes } => format!("{} ({} bytes)", kind, bytes.len()),
+        other => format!("{:?}", other),
+    }
+}
+
+fn window_text(window: IntegerBounds) -> String {
+    format!(
+        "({}, {}) - ({}, {})",
+        window.position.x(),
+        window.position.y(),
+        window.max().x(),
+        window.max().y()
+    )
+}
+
+fn window_json(window: IntegerBounds) -> String {
+    format!(
+        "{{\"x\": {}, \"y\": {}, \"width\": {}, \"height\": {}}}",
+        window.position.x(),
+        window.position.y(),
+        window.size.x(),
+        window.size.y()
+    )
+}
+
+fn attributes_json(attributes: &[(String, String)]) -> String {
+    let fields: Vec<String> = attributes
+        .iter()
+        .map(|(name, value)| format!("{}: {}", json_string(name), json_string(value)))
+        .collect();
+    format!("{{{}}}", fields.join(", "))
+}
+
+fn json_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
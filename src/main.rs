@@ -4,10 +4,15 @@
 
 use clap::{Arg, Command};
 use glob::glob;
-use oidn;
-
+mod deep;
+mod denoiser;
 mod image;
+mod inspect;
 mod licenses;
+mod pipeline;
+mod temporal;
+mod tiles;
+mod worker;
 
 fn main() {
     let matches = Command::new("My Test Program")
@@ -33,7 +38,7 @@ fn main() {
                 .help("a normal .exr file or sequence using the foo.####.exr pattern"),
         )
         .arg(Arg::new("layerbeauty").short('e').long("layerbeauty").help(
-            "OPTIONAL - name of layer to denoise inside the beauty exr, defaults to main_layer",
+            "OPTIONAL - name of layer to denoise inside the beauty exr, defaults to main_layer, takes a comma separated list, globs like \"*diffuse*\" or all for every RGB(A) color layer, data passes like cryptomattes are skipped",
         ))
         .arg(Arg::new("layeralbedo").short('l').long("layeralbedo").help(
             "OPTIONAL - name of layer to denoise inside the albedo exr, defaults to main_layer",
@@ -41,6 +46,174 @@ fn main() {
         .arg(Arg::new("layernormal").short('o').long("layernormal").help(
             "OPTIONAL - name of layer to denoise inside the normal exr, defaults to main_layer",
         ))
+        .arg(
+            Arg::new("precision")
+                .short('p')
+                .long("precision")
+                .value_parser(["half", "float", "same"])
+                .default_value("same")
+                .help("OPTIONAL - sample type of the denoised layer: half, float or same as the source channels"),
+        )
+        .arg(
+            Arg::new("compression")
+                .short('c')
+                .long("compression")
+                .value_parser(["same", "none", "rle", "zips", "zip", "piz", "pxr24", "b44", "b44a"])
+                .default_value("same")
+                .help("OPTIONAL - compression of the denoised exr, same keeps the compression of the source file"),
+        )
+        .arg(
+            Arg::new("level")
+                .long("level")
+                .default_value("0")
+                .help("OPTIONAL - resolution level to denoise in mip/rip-mapped exrs, 0 is the full resolution, or all"),
+        )
+        .arg(
+            Arg::new("quality")
+                .short('q')
+                .long("quality")
+                .value_parser(["default", "fast", "balanced", "high"])
+                .default_value("default")
+                .help("OPTIONAL - filter quality, fast and balanced trade quality for speed"),
+        )
+        .arg(
+            Arg::new("range")
+                .short('r')
+                .long("range")
+                .value_parser(["hdr", "ldr", "srgb"])
+                .default_value("hdr")
+                .help("OPTIONAL - hdr for renders, ldr for linear 0-1 images (tone mapped stills, bakes), srgb for srgb encoded 0-1 images"),
+        )
+        .arg(
+            Arg::new("inputscale")
+                .long("input-scale")
+                .default_value("default")
+                .help("OPTIONAL - multiply the colors by this before denoising, auto estimates the exposure of every hdr image, default lets oidn pick"),
+        )
+        .arg(
+            Arg::new("lightmap")
+                .long("lightmap")
+                .value_parser(["irradiance", "directional"])
+                .conflicts_with_all(["albedo", "normal", "prefilter", "cleanaux", "range"])
+                .help("OPTIONAL - denoise baked lightmaps with the lightmap filter, directional for directional lightmaps in -1..1"),
+        )
+        .arg(
+            Arg::new("temporal")
+                .long("temporal")
+                .value_parser(clap::value_parser!(usize))
+                .help("OPTIONAL - average this many frames before and after every frame of a sequence into it before denoising, against flicker"),
+        )
+        .arg(
+            Arg::new("motion")
+                .long("motion")
+                .requires("temporal")
+                .help("OPTIONAL - layer of the beauty exr with the motion in pixels since the previous frame, reprojects the frames used by --temporal"),
+        )
+        .arg(
+            Arg::new("weights")
+                .short('w')
+                .long("weights")
+                .help("OPTIONAL - custom trained weights, a .tza file or the name of one in $OIDN_CMD_WEIGHTS or ~/.config/oidn-cmd/weights"),
+        )
+        .arg(
+            Arg::new("cleanaux")
+                .long("clean-aux")
+                .num_args(0)
+                .help("OPTIONAL - the albedo and normal are noise free (e.g. first hit aovs), keeps more detail"),
+        )
+        .arg(
+            Arg::new("prefilter")
+                .long("prefilter")
+                .num_args(0)
+                .help("OPTIONAL - denoise the albedo and normal before the beauty, recommended for final frames"),
+        )
+        .arg(
+            Arg::new("threads")
+                .short('t')
+                .long("threads")
+                .value_parser(clap::value_parser!(i32))
+                .help("OPTIONAL - number of cpu threads to denoise with, defaults to all cores"),
+        )
+        .arg(
+            Arg::new("affinity")
+                .long("affinity")
+                .value_parser(["on", "off"])
+                .help("OPTIONAL - pin the cpu threads to cores, oidn decides by default"),
+        )
+        .arg(
+            Arg::new("maxmemory")
+                .long("max-memory")
+                .value_parser(clap::value_parser!(i32))
+                .help("OPTIONAL - memory limit of a filter in MB, larger images are denoised in tiles"),
+        )
+        .arg(
+            Arg::new("tile")
+                .long("tile")
+                .value_parser(clap::value_parser!(usize))
+                .help("OPTIONAL - denoise images larger than this many pixels wide or high in tiles of this size, the seams are blended"),
+        )
+        .arg(
+            Arg::new("tileoverlap")
+                .long("tile-overlap")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("64")
+                .help("OPTIONAL - pixels shared by neighbouring tiles that get blended, used with --tile"),
+        )
+        .arg(
+            Arg::new("workers")
+                .long("workers")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1")
+                .help("OPTIONAL - number of frames denoised at the same time, each with its own device and share of the cores"),
+        )
+        .arg(
+            Arg::new("iothreads")
+                .long("io-threads")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("1")
+                .help("OPTIONAL - number of threads reading and number of threads writing exrs while denoising"),
+        )
+        .arg(
+            Arg::new("queue")
+                .long("queue")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("2")
+                .help("OPTIONAL - number of frames read ahead of and waiting behind the denoiser"),
+        )
+        .arg(
+            Arg::new("maxqueuememory")
+                .long("max-queue-memory")
+                .value_parser(clap::value_parser!(usize))
+                .help("OPTIONAL - memory limit in MB of the frames read but not written yet"),
+        )
+        .arg(
+            Arg::new("recombine")
+                .long("recombine")
+                .help("OPTIONAL - add up the denoised layers and write the sum as a new layer with this name"),
+        )
+        .arg(
+            Arg::new("residual")
+                .long("residual")
+                .requires("recombine")
+                .help("OPTIONAL - layer of the original beauty to print the difference to the recombined layer against"),
+        )
+        .arg(
+            Arg::new("writeflat")
+                .long("write-flat")
+                .num_args(0)
+                .help("OPTIONAL - for deep exrs also write the flattened image before denoising as foo_flat.exr"),
+        )
+        .subcommand(
+            Command::new("inspect")
+                .about("list the layers, channels and header attributes of an exr")
+                .arg(Arg::new("file").required(true).help("the .exr file to inspect"))
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .num_args(0)
+                        .help("print the layers as json"),
+                ),
+        )
         .arg(
             Arg::new("license")
                 .short('x')
@@ -66,6 +239,12 @@ fn main() {
         )
     };
 
+    if let Some(inspect_matches) = matches.subcommand_matches("inspect") {
+        let file = inspect_matches.get_one::<String>("file").unwrap();
+        inspect::print_exr_info(file, inspect_matches.get_flag("json"));
+        return;
+    }
+
     let beauty_path: &String = matches
         .get_one::<String>("beauty")
         .expect("supply beauty exr file(s) using the -b flag");
@@ -84,14 +263,55 @@ fn main() {
         .get_one::<String>("layernormal")
         .unwrap_or(&default_layer_name);
 
+    let save_options = image::SaveOptions {
+        precision: image::Precision::from_name(matches.get_one::<String>("precision").unwrap()),
+        compression: image::compression_from_name(matches.get_one::<String>("compression").unwrap()),
+    };
+
+    let quality = denoiser::Quality::from_name(matches.get_one::<String>("quality").unwrap());
+    let range = denoiser::Range::from_name(matches.get_one::<String>("range").unwrap());
+    let input_scale = denoiser::InputScale::from_name(matches.get_one::<String>("inputscale").unwrap());
+    let clean_aux = matches.get_flag("cleanaux");
+    let weights: Option<(String, Vec<u8>)> = matches.get_one::<String>("weights").map(|name| denoiser::load_weights(name));
+    if let Some((path, _)) = &weights {
+        println!("weights: {}", path);
+    }
+    let lightmap: Option<&str> = matches.get_one::<String>("lightmap").map(String::as_str);
+    let prefilter = matches.get_flag("prefilter");
+
+    let device_options = denoiser::DeviceOptions {
+        threads: matches.get_one::<i32>("threads").copied(),
+        affinity: matches.get_one::<String>("affinity").map(|affinity| affinity == "on"),
+    };
+    let max_memory: Option<i32> = matches.get_one::<i32>("maxmemory").copied();
+    let tiling = matches
+        .get_one::<usize>("tile")
+        .map(|size| tiles::Tiling::new(*size, *matches.get_one::<usize>("tileoverlap").unwrap()));
+
+    let workers: usize = (*matches.get_one::<usize>("workers").unwrap()).max(1);
+    let io_threads: usize = (*matches.get_one::<usize>("iothreads").unwrap()).max(1);
+    let queue_depth: usize = *matches.get_one::<usize>("queue").unwrap();
+    let max_queue_memory: Option<usize> = matches.get_one::<usize>("maxqueuememory").copied();
+
+    let recombine: Option<&String> = matches.get_one::<String>("recombine");
+    let residual_layer: Option<&String> = matches.get_one::<String>("residual");
+
+    let level: Option<usize> = match matches.get_one::<String>("level").unwrap().as_str() {
+        "all" => None,
+        n => Some(n.parse().expect("--level has to be a number or all")),
+    };
+
     let (beauty_seq, albedo_seq, normal_seq) = {
         if beauty_path.contains("#") {
             let beauty_seq = gather_file_sequence(beauty_path.clone());
+            // a single albedo or normal file (e.g. a static turntable) is used for every frame
             let albedo_seq = match albedo_path {
+                Some(path) if !path.contains("#") => Some(vec![path.clone()]),
                 Some(path) => Some(gather_file_sequence(albedo_path.unwrap().clone())),
                 None => None,
             };
             let normal_seq = match normal_path {
+                Some(path) if !path.contains("#") => Some(vec![path.clone()]),
                 Some(path) => Some(gather_file_sequence(normal_path.unwrap().clone())),
                 None => None,
             };
@@ -123,10 +343,8 @@ fn main() {
     };
 
     // sequence sanity check
-    if *&albedo_seq.is_some() || *&normal_seq.is_some() {
-        if &beauty_seq.clone().len() != &albedo_seq.clone().unwrap().len()
-            || &beauty_seq.clone().len() != &normal_seq.clone().unwrap().len()
-        {
+    for aux_seq in [&albedo_seq, &normal_seq].into_iter().flatten() {
+        if beauty_seq.len() != aux_seq.len() && aux_seq.len() != 1 {
             panic!("sequences dont have the same frame count!")
         }
     }
@@ -135,72 +353,169 @@ fn main() {
     //println!("{:?}", &albedo_seq);
     //println!("{:?}", &normal_seq);
 
-    // Denoise
-    let device = oidn::Device::new();
-    for (i, beauty_file_path) in beauty_seq.iter().enumerate() {
-        let mut beauty_img = image::FloatImage::from_exr(beauty_file_path.clone());
-
-        let mut denoiser = oidn::RayTracing::new(&device);
-        denoiser
-            .srgb(false)
-            .hdr(true)
-            .image_dimensions(beauty_img.width, beauty_img.height);
-
-        let albedo_data = match albedo_seq.clone() {
-            Some(seq) => {
-                let albedo_img = image::FloatImage::from_exr(seq[i].clone());
-                let (alebdo_type, albedo_data) = albedo_img
-                    .buffers
-                    .get(albedo_layer)
-                    .expect(&format!("layer not found in albedo: {}", albedo_layer)[..]);
-                Some(albedo_data.clone())
-            }
-            None => None,
-        };
+    let options = worker::Options {
+        beauty_layer: beauty_layer.clone(),
+        albedo_layer: albedo_layer.clone(),
+        normal_layer: normal_layer.clone(),
+        albedo_seq,
+        normal_seq,
+        level,
+        precision: save_options.precision,
+        quality,
+        range,
+        input_scale,
+        clean_aux,
+        prefilter,
+        lightmap: lightmap.map(str::to_string),
+        weights,
+        max_memory,
+        tiling,
+        temporal: matches.get_one::<usize>("temporal").map(|radius| temporal::Temporal {
+            radius: *radius,
+            motion_layer: matches.get_one::<String>("motion").cloned(),
+            frames: beauty_seq.clone(),
+        }),
+        recombine: recombine.cloned(),
+        residual_layer: residual_layer.cloned(),
+    };
 
-        match normal_seq.clone() {
-            Some(seq) => {
-                let normal_img = image::FloatImage::from_exr(seq[i].clone());
-                let (normal_type, normal_data) = normal_img
-                    .buffers
-                    .get(normal_layer)
-                    .expect(&format!("layer not found in normal: {}", normal_layer)[..]);
-                denoiser.albedo_normal(&albedo_data.expect("Albedo data missing"), &normal_data);
+    // Denoise
+    // every worker slot gets its own device, on the cpu the cores are split between them unless --threads is given.
+    // A gpu is shared by the workers, thread settings would move them to the cpu.
+    let device_options = match (workers, device_options.threads) {
+        (1, _) | (_, Some(_)) => device_options,
+        _ if !denoiser::default_device_is_cpu() => device_options,
+        (workers, None) => {
+            let cores = std::thread::available_parallelism().map_or(1, |cores| cores.get());
+            denoiser::DeviceOptions {
+                threads: Some((cores / workers).max(1) as i32),
+                ..device_options
             }
-            None => {}
-        };
+        }
+    };
+    match max_memory {
+        Some(max_memory) => println!("filter memory limit: {} MB", max_memory),
+        None => println!("filter memory limit: oidn default"),
+    };
 
-        let (beauty_type, mut beauty_data) = beauty_img
-            .buffers
-            .get(beauty_layer)
-            .expect(&format!("layer not found in beauty: {}", beauty_layer)[..])
-            .clone();
-        let (mut beauty_data_rgb, mut beauty_data_a) = match beauty_type {
-            image::BufferType::RGBA => image::strip_alpha(beauty_data),
-            _ => (beauty_data, Vec::new()),
-        };
+    // frames are read ahead and written behind on their own threads while the workers denoise
+    let start = std::time::Instant::now();
+    let budget = pipeline::MemoryBudget::new(max_queue_memory.map(|mb| mb * 1024 * 1024));
+    // the noisy frames used as neighbours are shared by the workers and count against the same budget
+    let neighbours = options.temporal.as_ref().map(|temporal| temporal::NeighbourCache::new(temporal, &budget));
+    let write_flat = matches.get_flag("writeflat").then_some(&save_options);
+    let (results, write_failed): (Vec<worker::Summary>, Vec<pipeline::Failed>) = std::thread::scope(|scope| {
+        let _closer = budget.closer();
+        let frames = pipeline::spawn_readers(scope, &beauty_seq, io_threads, queue_depth, &budget, write_flat);
+        let frames = std::sync::Arc::new(std::sync::Mutex::new(frames));
+        let (writer, writer_threads) = pipeline::spawn_writers(scope, io_threads, queue_depth, &budget, &save_options);
 
-        println!("beauty type: {:?}", beauty_type);
+        let slots: Vec<_> = (0..workers)
+            .map(|slot| {
+                let frames = std::sync::Arc::clone(&frames);
+                let writer = writer.clone();
+                let (options, budget, neighbours, beauty_seq) = (&options, &budget, neighbours.as_ref(), &beauty_seq);
+                scope.spawn(move || {
+                    let device = denoiser::Device::new(&device_options);
+                    println!("worker {}: device: {}", slot, device.describe());
+                    let mut worker = worker::Worker::new(&device, options, neighbours);
 
-        denoiser
-            .filter_in_place(&mut beauty_data_rgb)
-            .expect("Invalid input image dimensions?");
+                    let mut done: Vec<String> = Vec::new();
+                    let mut failed: Vec<(String, String)> = Vec::new();
+                    loop {
+                        let frame = match frames.lock().unwrap().recv() {
+                            Ok(Ok(frame)) => frame,
+                            Ok(Err(read_failed)) => {
+                                if let Some(neighbours) = neighbours {
+                                    let index = beauty_seq.iter().position(|path| *path == read_failed.0);
+                                    neighbours.finish(index.unwrap());
+                                }
+                                failed.push(read_failed);
+                                continue;
+                            }
+                            Err(_) => break, // all frames are read
+                        };
+                        println!("worker {}: frame: {}", slot, frame.path);
 
-        if let Err(e) = device.get_error() {
-            println!("Error denosing image: {}", e.1);
-        }
+                        // a broken frame is reported in the summary instead of stopping the whole sequence
+                        let (index, bytes) = (frame.index, frame.bytes);
+                        let denoised = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                            worker.denoise_frame(index, frame.img)
+                        }));
+                        if let Some(neighbours) = neighbours {
+                            neighbours.finish(index);
+                        }
+                        match denoised {
+                            Ok(img) => {
+                                let written = pipeline::Frame {
+                                    index,
+                                    path: frame.path.replace(".exr", "_denoised.exr"),
+                                    img,
+                                    bytes,
+                                };
+                                match writer.send(written) {
+                                    Ok(()) => done.push(frame.path),
+                                    Err(unsent) => {
+                                        budget.release(unsent.0.bytes);
+                                        failed.push((frame.path, String::from("the exr writers stopped")));
+                                    }
+                                }
+                            }
+                            Err(e) => {
+                                budget.release(bytes);
+                                failed.push((frame.path, pipeline::panic_message(e)));
+                            }
+                        }
+                    }
+                    worker::Summary { slot, done, failed }
+                })
+            })
+            .collect();
 
-        let beauty_data_denoised = match beauty_type {
-            image::BufferType::RGBA => image::add_alpha(beauty_data_rgb, beauty_data_a),
-            _ => beauty_data_rgb,
-        };
-
-        beauty_img
-            .buffers
-            .insert(beauty_layer.clone(), (beauty_type, beauty_data_denoised));
+        // the writers finish the queued frames and end once every worker is done
+        drop(writer);
+        // a worker or writer that stopped outside of a frame (e.g. no device) still ends up in the summary
+        let results: Vec<worker::Summary> = slots
+            .into_iter()
+            .enumerate()
+            .map(|(slot, handle)| {
+                handle.join().unwrap_or_else(|e| worker::Summary {
+                    slot,
+                    done: Vec::new(),
+                    failed: vec![(format!("worker {}", slot), pipeline::panic_message(e))],
+                })
+            })
+            .collect();
+        let write_failed = writer_threads
+            .into_iter()
+            .flat_map(|writer| {
+                writer
+                    .join()
+                    .unwrap_or_else(|e| vec![(String::from("exr writer"), pipeline::panic_message(e))])
+            })
+            .collect();
+        (results, write_failed)
+    });
 
-        let out_file_path = beauty_file_path.replace(".exr", "_denoised.exr");
-        beauty_img.save_to_file(out_file_path, beauty_layer);
+    let n_denoised: usize = results.iter().map(|summary| summary.done.len()).sum();
+    let failed: Vec<&pipeline::Failed> =
+        results.iter().flat_map(|summary| &summary.failed).chain(&write_failed).collect();
+    println!(
+        "summary: {} frames written, {} failed in {:.1}s",
+        n_denoised.saturating_sub(write_failed.len()),
+        failed.len(),
+        start.elapsed().as_secs_f32()
+    );
+    for summary in &results {
+        println!("  worker {}: {} frames denoised", summary.slot, summary.done.len());
+    }
+    // a farm job has to see that frames are missing
+    let any_failed = !failed.is_empty();
+    for (path, message) in failed {
+        println!("  failed {}: {}", path, message);
+    }
+    if any_failed {
+        std::process::exit(1);
     }
 }
 
@@ -0,0 +1,183 @@
+// Reading and writing exrs runs on their own threads so it overlaps with denoising. The queues between the
+// threads are bounded by a frame count and optionally by the memory of all frames that are read but not written yet.
+
+use std::any::Any;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{Scope, ScopedJoinHandle};
+
+use crate::image::{FloatImage, SaveOptions};
+
+pub struct Frame {
+    pub index: usize, // position in the beauty sequence
+    pub path: String, // the beauty exr it was read from, or the exr to write
+    pub img: FloatImage,
+    pub bytes: usize, // taken from the memory budget until the frame is written
+}
+
+// a frame that couldn't be read, denoised or written: its path and what went wrong
+pub type Failed = (String, String);
+
+// bytes of the frames in flight, readers wait while the cap is reached
+pub struct MemoryBudget {
+    cap: Option<usize>,
+    used: Mutex<(usize, usize)>, // bytes of the frames in flight and of frames kept outside the queues (--temporal)
+    freed: Condvar,
+    closed: AtomicBool, // nothing gets released anymore, e.g. the denoiser panicked
+}
+
+// closes the budget when dropped, so waiting readers don't block the end of the thread scope
+pub struct BudgetCloser<'a>(&'a MemoryBudget);
+
+impl Drop for BudgetCloser<'_> {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::SeqCst);
+        let _used = self.0.used.lock();
+        self.0.freed.notify_all();
+    }
+}
+
+impl MemoryBudget {
+    pub fn new(cap: Option<usize>) -> MemoryBudget {
+        MemoryBudget {
+            cap,
+            used: Mutex::new((0, 0)),
+            freed: Condvar::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn closer(&self) -> BudgetCloser<'_> {
+        BudgetCloser(self)
+    }
+
+    // wait until the bytes fit, a frame larger than the cap is let through once nothing else is in flight
+    fn acquire(&self, bytes: usize) {
+        let mut used = self.used.lock().unwrap();
+        if let Some(cap) = self.cap {
+            while used.0 > 0 && used.0 + used.1 + bytes > cap && !self.closed.load(Ordering::SeqCst) {
+                used = self.freed.wait(used).unwrap();
+            }
+        }
+        used.0 += bytes;
+    }
+
+    // replace what was taken for a frame before it was read by what it really uses, without waiting as it is read
+    fn correct(&self, estimated: usize, bytes: usize) {
+        let mut used = self.used.lock().unwrap();
+        used.0 = used.0 - estimated + bytes;
+        self.freed.notify_all();
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.used.lock().unwrap().0 -= bytes;
+        self.freed.notify_all();
+    }
+
+    // count frames kept outside the queues, they hold readers back but are never waited for themselves as
+    // they are only freed once the frames after them are denoised
+    pub fn keep(&self, bytes: usize) {
+        self.used.lock().unwrap().1 += bytes;
+    }
+
+    pub fn unkeep(&self, bytes: usize) {
+        self.used.lock().unwrap().1 -= bytes;
+        self.freed.notify_all();
+    }
+}
+
+// the message of a caught panic
+pub fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => panic.downcast_ref::<&str>().map_or_else(String::new, |message| message.to_string()),
+    }
+}
+
+// read the beauty exrs on `threads` threads, frame i is read by thread i % threads, frames arrive as they are done.
+// A frame that can't be read is passed on as failed, the thread goes on with its next frame.
+pub fn spawn_readers<'scope>(
+    scope: &'scope Scope<'scope, '_>,
+    paths: &'scope [String],
+    threads: usize,
+    queue_depth: usize,
+    budget: &'scope MemoryBudget,
+    write_flat: Option<&'scope SaveOptions>,
+) -> Receiver<Result<Frame, Failed>> {
+    let (sender, receiver) = sync_channel::<Result<Frame, Failed>>(queue_depth);
+    for thread_index in 0..threads {
+        let sender = sender.clone();
+        scope.spawn(move || {
+            for (index, path) in paths.iter().enumerate().skip(thread_index).step_by(threads) {
+                // the memory is taken before decoding, so a waiting reader doesn't hold a frame above the cap
+                let estimated = FloatImage::estimate_memory_size(path);
+                budget.acquire(estimated);
+                let read = catch_unwind(|| {
+                    let img = FloatImage::from_exr(path.clone());
+                    if let (true, Some(save_options)) = (img.deep, write_flat) {
+                        img.save_to_file(path.replace(".exr", "_flat.exr"), save_options);
+                    }
+                    img
+                });
+
+                let frame = match read {
+                    Ok(img) => {
+                        let bytes = img.memory_size();
+                        budget.correct(estimated, bytes);
+                        Ok(Frame {
+                            index,
+                            path: path.clone(),
+                            img,
+                            bytes,
+                        })
+                    }
+                    Err(e) => {
+                        budget.release(estimated);
+                        Err((path.clone(), format!("reading failed: {}", panic_message(e))))
+                    }
+                };
+                if sender.send(frame).is_err() {
+                    return; // the denoiser stopped
+                }
+            }
+        });
+    }
+    receiver
+}
+
+// write the denoised frames on `threads` threads, the threads end when the returned sender is dropped and
+// return the frames they couldn't write
+pub fn spawn_writers<'scope>(
+    scope: &'scope Scope<'scope, '_>,
+    threads: usize,
+    queue_depth: usize,
+    budget: &'scope MemoryBudget,
+    save_options: &'scope SaveOptions,
+) -> (SyncSender<Frame>, Vec<ScopedJoinHandle<'scope, Vec<Failed>>>) {
+    let (sender, receiver) = sync_channel::<Frame>(queue_depth);
+    let receiver = Arc::new(Mutex::new(receiver));
+    let handles = (0..threads)
+        .map(|_| {
+            let receiver = Arc::clone(&receiver);
+            scope.spawn(move || {
+                let mut failed: Vec<Failed> = Vec::new();
+                loop {
+                    // the lock is only held while waiting for the next frame, not while writing it
+                    let frame = match receiver.lock().unwrap().recv() {
+                        Ok(frame) => frame,
+                        Err(_) => return failed,
+                    };
+                    let path = frame.path.clone();
+                    let written = catch_unwind(AssertUnwindSafe(|| frame.img.save_to_file(frame.path, save_options)));
+                    budget.release(frame.bytes);
+                    if let Err(e) = written {
+                        failed.push((path, format!("writing failed: {}", panic_message(e))));
+                    }
+                }
+            })
+        })
+        .collect();
+    (sender, handles)
+}
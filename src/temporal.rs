@@ -0,0 +1,234 @@
+// Temporal denoising of sequences. The noisy beauty of the neighbouring frames is reprojected onto the current frame
+// and averaged into it before denoising, so consecutive frames share most of their input and don't flicker.
+
+use exr::prelude::IntegerBounds;
+use std::sync::{Arc, Mutex};
+
+use crate::image::{self, BufferType, FloatImage};
+use crate::pipeline::MemoryBudget;
+
+pub struct Temporal {
+    pub radius: usize,                // frames used before and after the current one
+    pub motion_layer: Option<String>, // screen space motion in pixels since the previous frame, x right and y down
+    pub frames: Vec<String>,          // the beauty sequence
+}
+
+// the noisy frames used as neighbours, shared by all workers. A frame being denoised is added as it is, others are
+// read when a neighbour needs them. A frame is dropped once every frame within the radius of it is finished, its
+// memory counts against --max-queue-memory until then.
+pub struct NeighbourCache<'a> {
+    temporal: &'a Temporal,
+    budget: &'a MemoryBudget,
+    frames: Mutex<CachedFrames>,
+}
+
+// a frame read by whoever needs it first, the others wait for it instead of reading it again
+type CachedFrame = Arc<Mutex<Option<FloatImage>>>;
+
+struct CachedFrames {
+    images: Vec<(usize, CachedFrame, usize)>, // frame index, the image once loaded and its bytes
+    finished: Vec<bool>,
+    unfinished: usize, // the first frame that isn't finished
+}
+
+impl<'a> NeighbourCache<'a> {
+    pub fn new(temporal: &'a Temporal, budget: &'a MemoryBudget) -> NeighbourCache<'a> {
+        NeighbourCache {
+            temporal,
+            budget,
+            frames: Mutex::new(CachedFrames {
+                images: Vec::new(),
+                finished: vec![false; temporal.frames.len()],
+                unfinished: 0,
+            }),
+        }
+    }
+
+    // keep a copy of the noisy frame that is about to be denoised for its neighbours
+    pub fn add(&self, index: usize, img: &FloatImage) {
+        let entry = self.entry(index);
+        let mut image = entry.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if image.is_none() {
+            self.keep(index, img.memory_size());
+            *image = Some(img.clone());
+        }
+    }
+
+    // the frame is denoised (or failed), frames no unfinished frame needs anymore are dropped
+    pub fn finish(&self, index: usize) {
+        let mut frames = self.frames.lock().unwrap();
+        frames.finished[index] = true;
+        while frames.unfinished < frames.finished.len() && frames.finished[frames.unfinished] {
+            frames.unfinished += 1;
+        }
+        let (unfinished, all_finished) = (frames.unfinished, frames.unfinished == frames.finished.len());
+        let radius = self.temporal.radius;
+        let mut freed = 0;
+        frames.images.retain(|(i, _, bytes)| {
+            let needed = !all_finished && i + radius >= unfinished;
+            if !needed {
+                freed += bytes;
+            }
+            needed
+        });
+        self.budget.unkeep(freed);
+    }
+
+    // the layer of every neighbour of frame `index` as rgb in the window of the current frame, with its distance
+    pub fn rgb_layer(&self, index: usize, level: usize, layer: &str, window: IntegerBounds) -> Vec<(i32, Vec<f32>)> {
+        let first = index.saturating_sub(self.temporal.radius);
+        let last = (index + self.temporal.radius).min(self.temporal.frames.len() - 1);
+
+        let mut out: Vec<(i32, Vec<f32>)> = Vec::new();
+        for i in (first..=last).filter(|i| *i != index) {
+            let entry = self.entry(i);
+            // a frame that failed to read is left empty and fails again for the next frame that needs it
+            let mut image = entry.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if image.is_none() {
+                let img = FloatImage::from_exr(self.temporal.frames[i].clone());
+                self.keep(i, img.memory_size());
+                *image = Some(img);
+            }
+            let img = image.as_mut().unwrap();
+            if level != img.level {
+                img.load_level(level);
+            }
+
+            let (buffer_type, data) = match img.buffers.get(layer) {
+                Some(buffer) => buffer.clone(),
+                None => {
+                    println!("temporal: frame {} has no layer {}, leaving it out", i, layer);
+                    continue;
+                }
+            };
+            let rgb = match buffer_type {
+                BufferType::RGBA => image::strip_alpha(data).0,
+                BufferType::Mono => image::mono_to_rgb(data),
+                BufferType::Dual => image::dual_to_rgb(data),
+                _ => data,
+            };
+
+            let data_window = img.channel_maps[layer].data_window;
+            let rgb = match data_window == window {
+                true => rgb,
+                false => image::fit_to_window(&rgb, data_window, window),
+            };
+            out.push((i as i32 - index as i32, rgb));
+        }
+        out
+    }
+
+    // the cache entry of a frame, loading it is left to the caller so other frames aren't blocked meanwhile
+    fn entry(&self, index: usize) -> CachedFrame {
+        let mut frames = self.frames.lock().unwrap();
+        match frames.images.iter().find(|(i, _, _)| *i == index) {
+            Some((_, entry, _)) => Arc::clone(entry),
+            None => {
+                let entry = Arc::new(Mutex::new(None));
+                frames.images.push((index, Arc::clone(&entry), 0));
+                entry
+            }
+        }
+    }
+
+    fn keep(&self, index: usize, bytes: usize) {
+        let mut frames = self.frames.lock().unwrap();
+        if let Some((_, _, kept)) = frames.images.iter_mut().find(|(i, _, _)| *i == index) {
+            *kept = bytes;
+            self.budget.keep(bytes);
+        }
+    }
+}
+
+// the first two channels of the motion layer in the window of the beauty, scaled to the resolution level
+pub fn motion(img: &FloatImage, layer: &str, window: IntegerBounds, level: usize) -> Vec<[f32; 2]> {
+    let (_, data) = img
+        .buffers
+        .get(layer)
+        .unwrap_or_else(|| panic!("motion layer not found in beauty: {}", layer));
+    let data_window = img.channel_maps[layer].data_window;
+    let n_channels = data.len() / data_window.size.area();
+    if n_channels < 2 {
+        panic!("motion layer {} needs an x and y channel", layer);
+    }
+
+    let data = match data_window == window {
+        true => data.clone(),
+        false => image::fit_to_window(data, data_window, window),
+    };
+    let scale = 1.0 / (1 << level) as f32;
+    data.chunks_exact(n_channels)
+        .map(|pixel| [pixel[0] * scale, pixel[1] * scale])
+        .collect()
+}
+
+// average the reprojected neighbours into the current frame, closer frames weigh more. A reprojected sample is
+// clamped to the colors around the pixel in the current frame, so disocclusions and wrong motion don't ghost.
+pub fn accumulate(
+    current: &[f32],
+    neighbours: &[(i32, Vec<f32>)],
+    motion: Option<&[[f32; 2]]>,
+    width: usize,
+    height: usize,
+    radius: usize,
+) -> Vec<f32> {
+    let mut out = current.to_vec();
+    for y in 0..height {
+        for x in 0..width {
+            let i = x + y * width;
+
+            let mut low = [f32::MAX; 3];
+            let mut high = [f32::MIN; 3];
+            for around_y in y.saturating_sub(1)..(y + 2).min(height) {
+                for around_x in x.saturating_sub(1)..(x + 2).min(width) {
+                    let around_i = (around_x + around_y * width) * 3;
+                    for c in 0..3 {
+                        low[c] = low[c].min(current[around_i + c]);
+                        high[c] = high[c].max(current[around_i + c]);
+                    }
+                }
+            }
+
+            let mut sum = [current[i * 3], current[i * 3 + 1], current[i * 3 + 2]];
+            let mut total = 1.0;
+            for (distance, data) in neighbours {
+                // where the pixel was or will be, assuming it keeps moving the same way
+                let (dx, dy) = motion.map_or((0.0, 0.0), |motion| {
+                    (motion[i][0] * *distance as f32, motion[i][1] * *distance as f32)
+                });
+                let sample = match sample_bilinear(data, width, height, x as f32 + dx, y as f32 + dy) {
+                    Some(sample) => sample,
+                    None => continue, // moved in from outside the frame
+                };
+
+                let weight = (radius + 1 - distance.unsigned_abs() as usize) as f32 / (radius + 1) as f32;
+                for c in 0..3 {
+                    sum[c] += sample[c].clamp(low[c], high[c]) * weight;
+                }
+                total += weight;
+            }
+
+            for c in 0..3 {
+                out[i * 3 + c] = sum[c] / total;
+            }
+        }
+    }
+    out
+}
+
+fn sample_bilinear(data: &[f32], width: usize, height: usize, x: f32, y: f32) -> Option<[f32; 3]> {
+    if x < 0.0 || y < 0.0 || x > (width - 1) as f32 || y > (height - 1) as f32 {
+        return None;
+    }
+    let (x0, y0) = (x.floor() as usize, y.floor() as usize);
+    let (x1, y1) = ((x0 + 1).min(width - 1), (y0 + 1).min(height - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+
+    let mut out = [0.0; 3];
+    for (c, value) in out.iter_mut().enumerate() {
+        let top = data[(x0 + y0 * width) * 3 + c] * (1.0 - fx) + data[(x1 + y0 * width) * 3 + c] * fx;
+        let bottom = data[(x0 + y1 * width) * 3 + c] * (1.0 - fx) + data[(x1 + y1 * width) * 3 + c] * fx;
+        *value = top * (1.0 - fy) + bottom * fy;
+    }
+    Some(out)
+}
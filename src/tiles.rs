@@ -0,0 +1,190 @@
+// Denoising of images too large for a single filter run. The image is split into overlapping tiles of the same size,
+// every tile is denoised on its own and the overlaps are feathered so the seams don't show.
+
+use crate::denoiser::Filter;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Tiling {
+    pub size: usize,
+    pub overlap: usize, // pixels shared by neighbouring tiles, the blend ramp
+}
+
+impl Tiling {
+    pub fn new(size: usize, overlap: usize) -> Tiling {
+        if overlap * 2 >= size {
+            panic!("the tile overlap {} has to be less than half of the tile size {}", overlap, size);
+        }
+        Tiling { size, overlap }
+    }
+
+    // whether an image of this size is denoised in more than one tile
+    pub fn splits(&self, width: usize, height: usize) -> bool {
+        width > self.size || height > self.size
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Tile {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+// denoise like Filter::execute, split into tiles when the image is larger than the tile size. Settings that oidn
+// would otherwise derive from each tile on its own (inputScale) have to be set for the whole image before.
+pub fn execute(
+    filter: &mut Filter,
+    tiling: Option<Tiling>,
+    output_input: &str,
+    output: &mut [f32],
+    inputs: &[(&str, &[f32])],
+    width: usize,
+    height: usize,
+) -> Result<(), String> {
+    let tiling = match tiling {
+        Some(tiling) if tiling.splits(width, height) => tiling,
+        _ => return filter.execute(output_input, output, inputs, width, height),
+    };
+
+    let tiles = split(width, height, tiling);
+    println!("denoising in {} tiles of {}x{}", tiles.len(), tiles[0].width, tiles[0].height);
+
+    let mut blended = vec![0.0; output.len()];
+    let mut weights = vec![0.0; width * height];
+    for tile in &tiles {
+        let mut tile_output = crop(output, width, *tile);
+        let tile_inputs: Vec<(&str, Vec<f32>)> = inputs
+            .iter()
+            .map(|(name, data)| (*name, crop(data, width, *tile)))
+            .collect();
+        let tile_inputs: Vec<(&str, &[f32])> = tile_inputs.iter().map(|(name, data)| (*name, &data[..])).collect();
+
+        filter.execute(output_input, &mut tile_output, &tile_inputs, tile.width, tile.height)?;
+        blend(&mut blended, &mut weights, &tile_output, width, height, *tile, tiling.overlap);
+    }
+
+    // every pixel is covered by at least one tile with a weight above 0
+    for ((pixel, sum), weight) in output.chunks_exact_mut(3).zip(blended.chunks_exact(3)).zip(&weights) {
+        for (value, sum) in pixel.iter_mut().zip(sum) {
+            *value = sum / weight;
+        }
+    }
+    Ok(())
+}
+
+// tiles of the same size so the filter is only set up once, the last row and column are moved back to the image edge
+fn split(width: usize, height: usize, tiling: Tiling) -> Vec<Tile> {
+    let xs = positions(width, tiling);
+    let ys = positions(height, tiling);
+    ys.iter()
+        .flat_map(|&y| {
+            xs.iter().map(move |&x| Tile {
+                x,
+                y,
+                width: tiling.size.min(width),
+                height: tiling.size.min(height),
+            })
+        })
+        .collect()
+}
+
+fn positions(length: usize, tiling: Tiling) -> Vec<usize> {
+    if length <= tiling.size {
+        return vec![0];
+    }
+    let step = tiling.size - tiling.overlap;
+    let mut out: Vec<usize> = (0..).map(|i| i * step).take_while(|x| x + tiling.size < length).collect();
+    out.push(length - tiling.size);
+    out
+}
+
+fn crop(data: &[f32], width: usize, tile: Tile) -> Vec<f32> {
+    let mut out = Vec::with_capacity(tile.width * tile.height * 3);
+    for y in tile.y..tile.y + tile.height {
+        let start = (tile.x + y * width) * 3;
+        out.extend_from_slice(&data[start..start + tile.width * 3]);
+    }
+    out
+}
+
+// add the tile weighted by a ramp over the overlap, edges of the image are not faded
+fn blend(
+    blended: &mut [f32],
+    weights: &mut [f32],
+    tile_data: &[f32],
+    width: usize,
+    height: usize,
+    tile: Tile,
+    overlap: usize,
+) {
+    let ramp = |position: usize, size: usize, start: usize, length: usize| -> f32 {
+        let mut weight: f32 = 1.0;
+        if overlap > 0 && start > 0 {
+            weight = weight.min((position as f32 + 0.5) / overlap as f32);
+        }
+        if overlap > 0 && start + size < length {
+            weight = weight.min(((size - position) as f32 - 0.5) / overlap as f32);
+        }
+        weight
+    };
+
+    for y in 0..tile.height {
+        let weight_y = ramp(y, tile.height, tile.y, height);
+        for x in 0..tile.width {
+            let weight = weight_y * ramp(x, tile.width, tile.x, width);
+            let i = tile.x + x + (tile.y + y) * width;
+            let tile_i = (x + y * tile.width) * 3;
+            for c in 0..3 {
+                blended[i * 3 + c] += tile_data[tile_i + c] * weight;
+            }
+            weights[i] += weight;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions_end_on_the_edge() {
+        let tiling = Tiling::new(256, 32);
+        assert_eq!(positions(200, tiling), vec![0]);
+        assert_eq!(positions(256, tiling), vec![0]);
+        assert_eq!(positions(1000, tiling), vec![0, 224, 448, 672, 744]);
+        assert_eq!(positions(257, tiling), vec![0, 1]);
+        for length in [257, 480, 481, 1000, 4096] {
+            assert_eq!(*positions(length, tiling).last().unwrap(), length - tiling.size);
+        }
+    }
+
+    #[test]
+    fn split_covers_the_image() {
+        let tiling = Tiling::new(64, 8);
+        let tiles = split(150, 40, tiling);
+        assert_eq!(tiles.len(), 3);
+        for tile in &tiles {
+            assert_eq!((tile.width, tile.height), (64, 40));
+        }
+        assert_eq!(tiles.last().unwrap().x, 150 - 64);
+    }
+
+    #[test]
+    fn blend_weights_reach_every_pixel() {
+        for (width, height, size, overlap) in [(150, 40, 64, 8), (300, 300, 128, 0), (129, 129, 128, 63)] {
+            let tiling = Tiling::new(size, overlap);
+            let mut blended = vec![0.0; width * height * 3];
+            let mut weights = vec![0.0; width * height];
+            for tile in split(width, height, tiling) {
+                let data = vec![2.0; tile.width * tile.height * 3];
+                blend(&mut blended, &mut weights, &data, width, height, tile, overlap);
+            }
+            assert!(weights.iter().all(|weight| *weight > 0.0));
+            // the same value in every tile blends back to that value
+            for (i, weight) in weights.iter().enumerate() {
+                assert!((blended[i * 3] / weight - 2.0).abs() < 1e-5);
+            }
+        }
+    }
+}
@@ -0,0 +1,454 @@
+// Denoising of one frame, every worker slot has its own device, filters and albedo/normal cache.
+
+use crate::denoiser;
+use crate::image;
+use crate::pipeline;
+use crate::temporal;
+use crate::tiles;
+
+// everything from the command line that decides how a frame is denoised
+pub struct Options {
+    pub beauty_layer: String, // the --layerbeauty selection, see FloatImage::select_layers
+    pub albedo_layer: String,
+    pub normal_layer: String,
+    pub albedo_seq: Option<Vec<String>>, // one file per frame or a single file for all frames
+    pub normal_seq: Option<Vec<String>>,
+    pub level: Option<usize>, // None for all levels
+    pub precision: image::Precision,
+    pub quality: denoiser::Quality,
+    pub range: denoiser::Range,
+    pub input_scale: denoiser::InputScale,
+    pub clean_aux: bool,
+    pub prefilter: bool,
+    pub lightmap: Option<String>, // irradiance or directional
+    pub weights: Option<(String, Vec<u8>)>, // path and content of the .tza file
+    pub max_memory: Option<i32>,
+    pub tiling: Option<tiles::Tiling>,
+    pub temporal: Option<temporal::Temporal>,
+    pub recombine: Option<String>,
+    pub residual_layer: Option<String>,
+}
+
+// what a worker slot got through, printed at the end of the run
+pub struct Summary {
+    pub slot: usize,
+    pub done: Vec<String>,
+    pub failed: Vec<pipeline::Failed>, // frames that couldn't be read or denoised
+}
+
+// path, level, layer and beauty window of a prefiltered albedo or normal
+type AuxKey = (String, usize, String, exr::prelude::IntegerBounds);
+
+pub struct Worker<'a> {
+    options: &'a Options,
+    filter: denoiser::Filter<'a>,
+    aux_filters: Option<(denoiser::Filter<'a>, denoiser::Filter<'a>)>, // albedo and normal prefilters
+    albedo_cache: Option<(String, image::FloatImage)>, // the last albedo and normal exr read
+    normal_cache: Option<(String, image::FloatImage)>,
+    prefiltered_albedo: Vec<(AuxKey, Vec<f32>)>, // prefiltered layers of the albedo and normal files in use
+    prefiltered_normal: Vec<(AuxKey, Vec<f32>)>,
+    neighbours: Option<&'a temporal::NeighbourCache<'a>>, // the frames around the current one for --temporal
+}
+
+impl<'a> Worker<'a> {
+    // the filters are kept for the whole run, oidn only sets them up again when the image size changes
+    pub fn new(
+        device: &'a denoiser::Device,
+        options: &'a Options,
+        neighbours: Option<&'a temporal::NeighbourCache<'a>>,
+    ) -> Worker<'a> {
+        let mut filter = match options.lightmap.as_deref() {
+            Some(kind) => {
+                // lightmaps have no albedo or normal and are always hdr, directional ones are in -1..1
+                let mut filter = denoiser::Filter::new(device, "RTLightmap");
+                filter.set_bool("directional", kind == "directional");
+                filter
+            }
+            None => {
+                let mut filter = denoiser::Filter::new(device, "RT");
+                filter
+                    .range(options.range)
+                    .set_bool("cleanAux", options.clean_aux || options.prefilter);
+                filter
+            }
+        };
+        filter.quality(options.quality);
+        if let Some(max_memory) = options.max_memory {
+            filter.set_int("maxMemoryMB", max_memory);
+        }
+        if let Some((_, weights)) = &options.weights {
+            filter.weights(weights);
+        }
+
+        let aux_filters = match options.prefilter {
+            true => {
+                let mut albedo_filter = denoiser::Filter::new(device, "RT");
+                let mut normal_filter = denoiser::Filter::new(device, "RT");
+                for aux_filter in [&mut albedo_filter, &mut normal_filter] {
+                    aux_filter.quality(options.quality);
+                    if let Some(max_memory) = options.max_memory {
+                        aux_filter.set_int("maxMemoryMB", max_memory);
+                    }
+                }
+                Some((albedo_filter, normal_filter))
+            }
+            false => None,
+        };
+
+        Worker {
+            options,
+            filter,
+            aux_filters,
+            albedo_cache: None,
+            normal_cache: None,
+            prefiltered_albedo: Vec::new(),
+            prefiltered_normal: Vec::new(),
+            neighbours,
+        }
+    }
+
+    // denoise the selected layers and levels of frame `index` of the beauty sequence
+    pub fn denoise_frame(&mut self, index: usize, mut beauty_img: image::FloatImage) -> image::FloatImage {
+        let options = self.options;
+        if let Some(neighbours) = self.neighbours {
+            neighbours.add(index, &beauty_img);
+        }
+
+        let beauty_layers = beauty_img.select_layers(&options.beauty_layer);
+        println!("beauty layers: {}", beauty_layers.join(", "));
+
+        let levels = match options.level {
+            Some(level) => level..level + 1,
+            None => {
+                let level_count = beauty_layers.iter().map(|layer| beauty_img.level_count(layer)).max();
+                0..level_count.unwrap_or(1)
+            }
+        };
+        let mut recombine_key: Option<String> = None;
+        for level in levels {
+            if level != beauty_img.level {
+                beauty_img.load_level(level);
+            }
+            println!("level: {}", level);
+
+            // the albedo and normal files are read once and shared by all beauty layers
+            let albedo_path = options.albedo_seq.as_ref().map(|seq| seq[index.min(seq.len() - 1)].clone());
+            let normal_path = options.normal_seq.as_ref().map(|seq| seq[index.min(seq.len() - 1)].clone());
+            let albedo_img = albedo_path
+                .as_ref()
+                .map(|path| load_aux_image(&mut self.albedo_cache, path, level));
+            let normal_img = normal_path
+                .as_ref()
+                .map(|path| load_aux_image(&mut self.normal_cache, path, level));
+
+            // the residual is taken against the beauty before it gets denoised
+            let residual_source = options.residual_layer.as_ref().map(|layer| {
+                beauty_img
+                    .buffers
+                    .get(layer)
+                    .unwrap_or_else(|| panic!("layer not found in beauty: {}", layer))
+                    .clone()
+            });
+
+            let mut denoised_layers: Vec<String> = Vec::new();
+            for beauty_layer in &beauty_layers {
+                if !beauty_img.buffers.contains_key(beauty_layer) {
+                    println!("layer {} has no level {}, skipping it", beauty_layer, level);
+                    continue;
+                }
+                denoised_layers.push(beauty_layer.clone());
+                println!("layer: {}", beauty_layer);
+
+                let beauty_window = beauty_img.channel_maps[beauty_layer].data_window;
+
+                let (width, height) = (beauty_window.size.x(), beauty_window.size.y());
+                let display_window = beauty_img.attributes.display_window;
+                let mut albedo_data = match &albedo_img {
+                    Some(img) => Some(fit_aux_layer("albedo", img, &options.albedo_layer, beauty_window, &display_window)),
+                    None if options.lightmap.is_some() => None,
+                    // no albedo file, look for a denoising albedo inside the beauty exr
+                    None => beauty_img
+                        .find_aov(beauty_layer, &image::ALBEDO_AOV_NAMES)
+                        .map(|layer| {
+                            println!("albedo: using layer {} of the beauty exr", layer);
+                            fit_aux_layer("albedo", &beauty_img, &layer, beauty_window, &display_window)
+                        }),
+                };
+
+                let mut normal_data = match &normal_img {
+                    // the albedo can also come from the beauty exr, so this is only known here
+                    Some(_) if albedo_data.is_none() => {
+                        panic!("a normal pass needs an albedo pass as well, supply it with -a or as a beauty layer")
+                    }
+                    Some(img) => Some(fit_aux_layer("normal", img, &options.normal_layer, beauty_window, &display_window)),
+                    None if options.lightmap.is_some() => None,
+                    None => match (&albedo_data, beauty_img.find_aov(beauty_layer, &image::NORMAL_AOV_NAMES)) {
+                        (Some(_), Some(layer)) => {
+                            println!("normal: using layer {} of the beauty exr", layer);
+                            Some(fit_aux_layer("normal", &beauty_img, &layer, beauty_window, &display_window))
+                        }
+                        (None, Some(layer)) => {
+                            println!("normal: ignoring layer {} of the beauty exr, it needs an albedo", layer);
+                            None
+                        }
+                        (_, None) => None,
+                    },
+                };
+
+                // denoise the albedo and normal with their own filters first, the beauty filter then treats them as clean
+                if let Some((albedo_filter, normal_filter)) = self.aux_filters.as_mut() {
+                    if let Some(albedo_data) = albedo_data.as_mut() {
+                        let key = albedo_path
+                            .as_ref()
+                            .map(|path| (path.clone(), level, options.albedo_layer.clone(), beauty_window));
+                        let cache = &mut self.prefiltered_albedo;
+                        prefilter("albedo", albedo_filter, cache, key, albedo_data, options.tiling, (width, height));
+                    }
+                    if let Some(normal_data) = normal_data.as_mut() {
+                        let key = normal_path
+                            .as_ref()
+                            .map(|path| (path.clone(), level, options.normal_layer.clone(), beauty_window));
+                        let cache = &mut self.prefiltered_normal;
+                        prefilter("normal", normal_filter, cache, key, normal_data, options.tiling, (width, height));
+                    }
+                }
+
+                let mut aux_inputs: Vec<(&str, &[f32])> = Vec::new();
+                if let Some(albedo_data) = &albedo_data {
+                    aux_inputs.push(("albedo", albedo_data));
+                }
+                if let Some(normal_data) = &normal_data {
+                    aux_inputs.push(("normal", normal_data));
+                }
+
+                let (beauty_type, beauty_data) = beauty_img.buffers[beauty_layer].clone();
+                let (mut beauty_data_rgb, beauty_data_a) = match beauty_type {
+                    image::BufferType::RGBA => image::strip_alpha(beauty_data),
+                    image::BufferType::Mono => (image::mono_to_rgb(beauty_data), Vec::new()),
+                    image::BufferType::Dual => (image::dual_to_rgb(beauty_data), Vec::new()),
+                    _ => (beauty_data, Vec::new()),
+                };
+
+                println!(
+                    "beauty type: {:?} ({})",
+                    beauty_type,
+                    beauty_img.channel_maps[beauty_layer].describe()
+                );
+
+                if let (Some(temporal), Some(neighbours)) = (&options.temporal, self.neighbours) {
+                    let neighbours = neighbours.rgb_layer(index, level, beauty_layer, beauty_window);
+                    let motion = temporal
+                        .motion_layer
+                        .as_ref()
+                        .map(|layer| temporal::motion(&beauty_img, layer, beauty_window, level));
+                    println!(
+                        "temporal: averaging {} neighbouring frames{}",
+                        neighbours.len(),
+                        if motion.is_some() { ", reprojected" } else { "" }
+                    );
+                    beauty_data_rgb = temporal::accumulate(
+                        &beauty_data_rgb,
+                        &neighbours,
+                        motion.as_deref(),
+                        width,
+                        height,
+                        temporal.radius,
+                    );
+                }
+
+                let hdr = match options.lightmap.as_deref() {
+                    Some(kind) => kind != "directional",
+                    None => options.range == denoiser::Range::Hdr,
+                };
+                match options.input_scale {
+                    // oidn estimates the exposure of hdr input from what it gets, which would be every tile on its own
+                    denoiser::InputScale::Default => {
+                        let tiled = options.tiling.is_some_and(|tiling| tiling.splits(width, height));
+                        let scale = match tiled && hdr {
+                            true => {
+                                let scale = denoiser::auto_exposure(&beauty_data_rgb, width, height);
+                                println!("input scale: {} (estimated on the whole image for the tiles)", scale);
+                                scale
+                            }
+                            false => f32::NAN, // oidn's own estimate
+                        };
+                        self.filter.set_float("inputScale", scale);
+                    }
+                    // ldr and directional input has to stay in its range, a scale above 1 would clip the highlights
+                    denoiser::InputScale::Auto if !hdr => {
+                        println!("input scale: auto ignored, it only applies to hdr input");
+                    }
+                    denoiser::InputScale::Auto => {
+                        let scale = denoiser::auto_exposure(&beauty_data_rgb, width, height);
+                        println!("input scale: {} (auto)", scale);
+                        self.filter.set_float("inputScale", scale);
+                    }
+                    denoiser::InputScale::Fixed(scale) => {
+                        self.filter.set_float("inputScale", scale);
+                    }
+                };
+
+                let denoised = tiles::execute(
+                    &mut self.filter,
+                    options.tiling,
+                    "color",
+                    &mut beauty_data_rgb,
+                    &aux_inputs,
+                    width,
+                    height,
+                );
+                if let Err(e) = denoised {
+                    match &options.weights {
+                        // weights are trained for one filter and set of inputs, oidn rejects them otherwise
+                        Some((path, _)) => {
+                            let mut inputs = vec!["color"];
+                            inputs.extend(aux_inputs.iter().map(|(name, _)| *name));
+                            panic!(
+                                "the weights {} don't match the {} filter with {} input: {}",
+                                path,
+                                options.lightmap.as_deref().unwrap_or("ray tracing"),
+                                inputs.join(", "),
+                                e
+                            );
+                        }
+                        None => println!("Error denosing image: {}", e),
+                    }
+                }
+
+                let beauty_data_denoised = match beauty_type {
+                    image::BufferType::RGBA => image::add_alpha(beauty_data_rgb, beauty_data_a),
+                    image::BufferType::Mono => image::rgb_to_mono(beauty_data_rgb),
+                    image::BufferType::Dual => image::rgb_to_dual(beauty_data_rgb),
+                    _ => beauty_data_rgb,
+                };
+
+                beauty_img
+                    .buffers
+                    .insert(beauty_layer.clone(), (beauty_type, beauty_data_denoised));
+                beauty_img.store_layer(beauty_layer, options.precision);
+            }
+
+            if let (Some(recombine), false) = (&options.recombine, denoised_layers.is_empty()) {
+                let sum = beauty_img.sum_layers(&denoised_layers);
+                if let Some(original) = &residual_source {
+                    let (mean, max) = image::residual(original, &sum);
+                    println!(
+                        "residual {} - {}: mean {:?}, max {:?}",
+                        options.residual_layer.as_ref().unwrap(),
+                        recombine,
+                        mean,
+                        max
+                    );
+                }
+
+                // the channels are added once, later levels load them like any other layer
+                let recombine_key = recombine_key
+                    .get_or_insert_with(|| beauty_img.add_rgb_layer(recombine, &denoised_layers[0]))
+                    .clone();
+                println!("recombined {} into {}", denoised_layers.join(" + "), recombine_key);
+                beauty_img
+                    .buffers
+                    .insert(recombine_key.clone(), (image::BufferType::RGB, sum));
+                beauty_img.store_layer(&recombine_key, options.precision);
+            }
+        }
+
+        // every denoised layer and level is stored in the layers
+        beauty_img
+    }
+}
+
+// read an albedo or normal exr at the resolution level that gets denoised, the exr read last is reused
+fn load_aux_image<'a>(
+    cache: &'a mut Option<(String, image::FloatImage)>,
+    path: &str,
+    level: usize,
+) -> &'a image::FloatImage {
+    if cache.as_ref().is_none_or(|(cached_path, _)| cached_path != path) {
+        *cache = Some((path.to_string(), image::FloatImage::from_exr(path.to_string())));
+    }
+    let (_, img) = cache.as_mut().unwrap();
+    if level != img.level {
+        img.load_level(level);
+    }
+    img
+}
+
+// prefilter an albedo or normal in place, the result for a layer of an aux file is kept (`key`) as the same file is
+// often used for every frame of a sequence
+fn prefilter(
+    kind: &str,
+    filter: &mut denoiser::Filter,
+    cache: &mut Vec<(AuxKey, Vec<f32>)>,
+    key: Option<AuxKey>,
+    data: &mut Vec<f32>,
+    tiling: Option<tiles::Tiling>,
+    (width, height): (usize, usize),
+) {
+    if let Some((_, prefiltered)) = cache.iter().find(|(cached_key, _)| Some(cached_key) == key.as_ref()) {
+        println!("prefiltering {}: reusing the result of the last frame", kind);
+        data.clone_from(prefiltered);
+        return;
+    }
+
+    println!("prefiltering {}", kind);
+    if let Err(e) = tiles::execute(filter, tiling, kind, data, &[], width, height) {
+        println!("Error prefiltering {}: {}", kind, e);
+        return;
+    }
+    if let Some(key) = key {
+        // only the layers of the file in use are kept
+        cache.retain(|(cached_key, _)| cached_key.0 == key.0);
+        cache.push((key, data.clone()));
+    }
+}
+
+// place an albedo or normal layer of an already loaded exr into the window of the beauty layer
+fn fit_aux_layer(
+    kind: &str,
+    img: &image::FloatImage,
+    layer: &str,
+    beauty_window: exr::prelude::IntegerBounds,
+    display_window: &exr::prelude::IntegerBounds,
+) -> Vec<f32> {
+    let (buffer_type, data) = img
+        .buffers
+        .get(layer)
+        .unwrap_or_else(|| panic!("layer not found in {}: {}", kind, layer));
+    let channel_map = &img.channel_maps[layer];
+    println!("{} type: {:?} ({})", kind, buffer_type, channel_map.describe());
+
+    // the filter takes RGB, a grey albedo is fine but a normal needs all three directions
+    let data = match (buffer_type, kind) {
+        (image::BufferType::RGB, _) => data.clone(),
+        (image::BufferType::RGBA, _) => image::strip_alpha(data.clone()).0,
+        (image::BufferType::Mono, "albedo") => image::mono_to_rgb(data.clone()),
+        _ => panic!(
+            "{} layer {} is {:?}, it needs RGB or RGBA channels ({})",
+            kind,
+            layer,
+            buffer_type,
+            channel_map.describe()
+        ),
+    };
+
+    if channel_map.data_window == beauty_window {
+        return data;
+    }
+
+    // a cropped or overscan window is fine as long as it has every beauty pixel that is visible,
+    // anything else (e.g. a half resolution part) doesn't line up with the beauty
+    let visible = image::intersect(beauty_window, *display_window);
+    if visible.is_some_and(|visible| !channel_map.data_window.contains(visible)) {
+        panic!(
+            "{} layer {} ({:?}) doesn't match the resolution of the beauty layer ({:?})",
+            kind, layer, channel_map.data_window, beauty_window
+        );
+    }
+
+    println!(
+        "{} data window {:?} differs from beauty {:?}, fitting it to the beauty",
+        kind, channel_map.data_window, beauty_window
+    );
+    image::fit_to_window(&data, channel_map.data_window, beauty_window)
+}